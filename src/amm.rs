@@ -1,11 +1,32 @@
 use core::convert::TryFrom;
 use primitive_types::U256;
-use crate::MathError::{ZeroInReserve, Overflow, InsufficientOutReserve};
+use crate::math::{integer_sqrt, pow_fixed, pow_with_fraction, FIXED_ONE};
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+use crate::MathError::{ZeroInReserve, Overflow, InsufficientOutReserve, InvalidTime, PrecisionLoss, Inexact, InsufficientLiquidity, KExceeded, SlippageExceeded, ExcessivePriceImpact, StaleReserves, PriceBoundBreached, InvalidFee, VolumeCapExceeded};
 
 type Balance = u128;
 
+/// Fee charged on the amount in, as a (numerator, denominator) fraction, e.g. `(3, 1000)` for 0.3%.
+pub type Fee = (u32, u32);
+
 const FIXED_ROUND_UP: Balance = 1;
 
+const Q96_SHIFT: usize = 96;
+
+const BPS_ONE: Balance = 10_000;
+
+const MIN_ROUTABLE_DEPTH: Balance = 1_000;
+
+const PARTIAL_FILL_SCAN: Balance = 1_000;
+
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+const BLOCK_SECONDS: u64 = 12;
+
+#[cfg(feature = "alloc")]
+const RECIPROCAL_SHIFT: usize = 192;
+
 macro_rules! ensure {
     ($e:expr, $f:expr) => {
         match $e {
@@ -41,6 +62,54 @@ pub enum MathError {
     ZeroInReserve,
     Overflow,
     InsufficientOutReserve,
+    InvalidTime,
+    PrecisionLoss,
+    Inexact,
+    InsufficientLiquidity,
+    KExceeded,
+    SlippageExceeded,
+    ExcessivePriceImpact,
+    StaleReserves,
+    PriceBoundBreached,
+    InvalidFee,
+    VolumeCapExceeded,
+}
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum SwapDirection {
+    /// Selling asset in to the pool
+    Sell,
+    /// Buying asset in from the pool, selling asset out
+    Buy,
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct PoolState {
+    /// Reserve amount of asset a
+    pub reserve_a: Balance,
+    /// Reserve amount of asset b
+    pub reserve_b: Balance,
+    /// Total shares of the pool
+    pub total_shares: Balance,
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Op {
+    /// Selling the amount of asset a for asset b
+    SwapAForB(Balance),
+    /// Selling the amount of asset b for asset a
+    SwapBForA(Balance),
+    /// Depositing amounts of asset a and asset b
+    AddLiquidity(Balance, Balance),
+    /// Burning the amount of shares
+    RemoveLiquidity(Balance),
 }
 
 /// Calculating spot price given reserve of selling asset and reserve of buying asset.
@@ -68,6 +137,7 @@ pub fn calculate_spot_price(in_reserve: Balance, out_reserve: Balance, amount: B
 }
 
 /// Calculating amount to be received from the pool given the amount to be sent to the pool and both reserves.
+/// The result is rounded down, so the pool never pays out more than the curve allows.
 /// Formula : OUT_RESERVE * AMOUNT_IN / (IN_RESERVE + AMOUNT_IN)
 ///
 /// - `in_reserve` - reserve amount of selling asset
@@ -84,11 +154,11 @@ pub fn calculate_out_given_in(in_reserve: Balance, out_reserve: Balance, amount_
     let numerator = out_reserve_hp.checked_mul(amount_in_hp).ok_or(Overflow)?;
     let sale_price_hp = numerator.checked_div(denominator).ok_or(Overflow)?;
 
-    let result = to_balance!(sale_price_hp).ok();
-    round_up!(result.ok_or(Overflow)?)
+    to_balance!(sale_price_hp)
 }
 
 /// Calculating amount to be sent to the pool given the amount to be received from the pool and both reserves.
+/// The result is rounded up when the division is not exact, so the pool is never underpaid.
 /// Formula : IN_RESERVE * AMOUNT_OUT / (OUT_RESERVE - AMOUNT_OUT)
 ///
 /// - `in_reserve` - reserve amount of selling asset
@@ -104,10 +174,116 @@ pub fn calculate_in_given_out(out_reserve: Balance, in_reserve: Balance, amount_
     let numerator = in_reserve_hp.checked_mul(amount_out_hp).ok_or(Overflow)?;
     let denominator = out_reserve_hp.checked_sub(amount_out_hp).ok_or(Overflow)?;
     ensure!(!denominator.is_zero(), ZeroInReserve);
-    let buy_price_hp = numerator.checked_div(denominator).ok_or(Overflow)?;
+    let (buy_price_hp, remainder) = numerator.div_mod(denominator);
+
+    match remainder.is_zero() {
+        true => to_balance!(buy_price_hp),
+        false => round_up!(to_balance!(buy_price_hp)?),
+    }
+}
+
+/// Calculating spot price of a weighted pool given the weights of both assets.
+/// Formula : AMOUNT * (OUT_RESERVE / OUT_WEIGHT) / (IN_RESERVE / IN_WEIGHT)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `in_weight` - weight of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `out_weight` - weight of buying asset
+/// - `amount` - amount
+///
+/// Returns MathError in case of error
+pub fn calculate_spot_price_weighted(in_reserve: Balance, in_weight: u32, out_reserve: Balance, out_weight: u32, amount: Balance) -> Result<Balance, MathError> {
+    ensure!(in_reserve != 0 && in_weight != 0 && out_weight != 0, ZeroInReserve);
+
+    if amount == 0 || out_reserve == 0 {
+        return to_balance!(0);
+    }
+
+    let (amount_hp, out_reserve_hp, in_reserve_hp) = to_u256!(amount, out_reserve, in_reserve);
+
+    let numerator = amount_hp
+        .checked_mul(out_reserve_hp).ok_or(Overflow)?
+        .checked_mul(U256::from(in_weight)).ok_or(Overflow)?;
+    let denominator = in_reserve_hp.checked_mul(U256::from(out_weight)).ok_or(Overflow)?;
+
+    to_balance!(numerator / denominator)
+}
+
+/// Calculating amount to be received from a weighted pool given the amount to be sent to the pool.
+/// The ratio and its power are rounded up and the result is rounded down, so the pool never pays out more than the curve allows.
+/// With equal weights the result is at most 3 * OUT_RESERVE / 1e18 + 1 below calculate_out_given_in, and never above it.
+/// Formula : OUT_RESERVE * (1 - (IN_RESERVE / (IN_RESERVE + AMOUNT_IN)) ^ (IN_WEIGHT / OUT_WEIGHT))
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `in_weight` - weight of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `out_weight` - weight of buying asset
+/// - `amount_in` - amount
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_weighted(in_reserve: Balance, in_weight: u32, out_reserve: Balance, out_weight: u32, amount_in: Balance) -> Result<Balance, MathError> {
+    ensure!(in_weight != 0 && out_weight != 0, ZeroInReserve);
+
+    let (in_reserve_hp, out_reserve_hp, amount_in_hp) = to_u256!(in_reserve, out_reserve, amount_in);
+    let fixed_one = U256::from(FIXED_ONE);
+
+    let denominator = in_reserve_hp.checked_add(amount_in_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let (ratio_hp, remainder) = in_reserve_hp.checked_mul(fixed_one).ok_or(Overflow)?.div_mod(denominator);
+    let ratio_hp = match remainder.is_zero() {
+        true => ratio_hp,
+        false => ratio_hp + 1,
+    };
+
+    let kept_hp = pow_with_fraction(ratio_hp, in_weight, out_weight)?;
+    let kept_hp = kept_hp.checked_add(kept_hp / fixed_one + 1).ok_or(Overflow)?;
+    let paid_hp = fixed_one.saturating_sub(kept_hp);
+
+    to_balance!(out_reserve_hp.checked_mul(paid_hp).ok_or(Overflow)? / fixed_one)
+}
+
+/// Calculating amount to be sent to a weighted pool given the amount to be received from the pool.
+/// The ratio, its power and the result are rounded up, so the pool is never underpaid.
+/// Formula : IN_RESERVE * ((OUT_RESERVE / (OUT_RESERVE - AMOUNT_OUT)) ^ (OUT_WEIGHT / IN_WEIGHT) - 1)
+///
+/// - `out_reserve` - reserve amount of buying asset
+/// - `out_weight` - weight of buying asset
+/// - `in_reserve` - reserve amount of selling asset
+/// - `in_weight` - weight of selling asset
+/// - `amount_out` - buy amount
+///
+/// Returns MathError in case of error
+pub fn calculate_in_given_out_weighted(out_reserve: Balance, out_weight: u32, in_reserve: Balance, in_weight: u32, amount_out: Balance) -> Result<Balance, MathError> {
+    ensure!(in_weight != 0 && out_weight != 0, ZeroInReserve);
+    ensure!(amount_out <= out_reserve, InsufficientOutReserve);
+
+    if amount_out == 0 {
+        return to_balance!(0);
+    }
+
+    let (out_reserve_hp, in_reserve_hp, amount_out_hp) = to_u256!(out_reserve, in_reserve, amount_out);
+    let fixed_one = U256::from(FIXED_ONE);
+
+    let denominator = out_reserve_hp - amount_out_hp;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let (ratio_hp, remainder) = out_reserve_hp.checked_mul(fixed_one).ok_or(Overflow)?.div_mod(denominator);
+    let ratio_hp = match remainder.is_zero() {
+        true => ratio_hp,
+        false => ratio_hp + 1,
+    };
+
+    let grown_hp = pow_with_fraction(ratio_hp, out_weight, in_weight)?;
+    let grown_hp = grown_hp.checked_add(grown_hp / fixed_one + 1).ok_or(Overflow)?;
+    let extra_hp = grown_hp.checked_sub(fixed_one).ok_or(Overflow)?;
 
-    let result = to_balance!(buy_price_hp).ok();
-    round_up!(result.ok_or(Overflow)?)
+    let (amount_in_hp, remainder) = in_reserve_hp.checked_mul(extra_hp).ok_or(Overflow)?.div_mod(fixed_one);
+
+    match remainder.is_zero() {
+        true => to_balance!(amount_in_hp),
+        false => round_up!(to_balance!(amount_in_hp)?),
+    }
 }
 
 /// Calculating required amount of asset b given asset a.
@@ -163,4 +339,2398 @@ pub fn calculate_liquidity_out(
     let remove_amount_b = to_balance!(remove_amount_b_hp)?;
 
     Ok((remove_amount_a, remove_amount_b))
-}
\ No newline at end of file
+}
+
+/// Calculating reserves at a point in time by linear interpolation between two timestamped snapshots.
+/// Formula : RESERVE_A + (RESERVE_B - RESERVE_A) * (AT_TIME - TIME_A) / (TIME_B - TIME_A)
+///
+/// - `snap_a` - earlier snapshot as (asset a reserve, asset b reserve, timestamp)
+/// - `snap_b` - later snapshot as (asset a reserve, asset b reserve, timestamp)
+/// - `at_time` - timestamp to interpolate at, must be within the snapshot range
+///
+/// Returns MathError in case of error
+pub fn interpolate_reserves(
+    snap_a: (Balance, Balance, u64),
+    snap_b: (Balance, Balance, u64),
+    at_time: u64,
+) -> Result<(Balance, Balance), MathError> {
+    let (reserve_a_start, reserve_b_start, time_start) = snap_a;
+    let (reserve_a_end, reserve_b_end, time_end) = snap_b;
+
+    ensure!(time_start <= at_time && at_time <= time_end, InvalidTime);
+
+    if time_start == time_end {
+        return Ok((reserve_a_start, reserve_b_start));
+    }
+
+    let elapsed = at_time - time_start;
+    let period = time_end - time_start;
+
+    let reserve_a = interpolate(reserve_a_start, reserve_a_end, elapsed, period)?;
+    let reserve_b = interpolate(reserve_b_start, reserve_b_end, elapsed, period)?;
+
+    Ok((reserve_a, reserve_b))
+}
+
+fn interpolate(start: Balance, end: Balance, elapsed: u64, period: u64) -> Result<Balance, MathError> {
+    let (elapsed_hp, period_hp) = to_u256!(elapsed, period);
+
+    let delta_hp = U256::from(start.max(end) - start.min(end))
+        .checked_mul(elapsed_hp).ok_or(Overflow)?
+        .checked_div(period_hp).ok_or(Overflow)?;
+
+    let delta = to_balance!(delta_hp)?;
+
+    if end >= start {
+        start.checked_add(delta).ok_or(Overflow)
+    } else {
+        start.checked_sub(delta).ok_or(Overflow)
+    }
+}
+
+/// Calculating amount to be received from the pool given the amount to be sent to the pool, both reserves and fee.
+/// The result is rounded down. A zero fee gives the same result as `calculate_out_given_in`,
+/// and a trade too small to be worth a whole unit once the fee is deducted receives nothing.
+/// Formula : OUT_RESERVE * AMOUNT_IN * (FEE_DEN - FEE_NUM) / (IN_RESERVE * FEE_DEN + AMOUNT_IN * (FEE_DEN - FEE_NUM))
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_with_fee(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+
+    let (fee_numerator, fee_denominator) = fee;
+    if fee_numerator == 0 {
+        return calculate_out_given_in(in_reserve, out_reserve, amount_in);
+    }
+
+    let (in_reserve_hp, out_reserve_hp, amount_in_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(in_reserve, out_reserve, amount_in, fee_numerator, fee_denominator);
+
+    let amount_in_after_fee_hp = amount_in_hp
+        .checked_mul(fee_denominator_hp - fee_numerator_hp).ok_or(Overflow)?;
+
+    if amount_in != 0 && amount_in_after_fee_hp < fee_denominator_hp {
+        return Ok(0);
+    }
+
+    let denominator = in_reserve_hp
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?
+        .checked_add(amount_in_after_fee_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let numerator = out_reserve_hp.checked_mul(amount_in_after_fee_hp).ok_or(Overflow)?;
+    let sale_price_hp = numerator.checked_div(denominator).ok_or(Overflow)?;
+
+    to_balance!(sale_price_hp)
+}
+
+/// Calculating amount to be sent to the pool given the amount to be received from the pool, both reserves and fee.
+/// The input is grossed up so that it still buys `amount_out` once the fee is deducted, and rounded up when the division
+/// is not exact. A zero fee gives the same result as `calculate_in_given_out`, and a fee of the whole amount
+/// is invalid as nothing could be bought.
+/// Formula : IN_RESERVE * AMOUNT_OUT * FEE_DEN / ((OUT_RESERVE - AMOUNT_OUT) * (FEE_DEN - FEE_NUM))
+///
+/// - `out_reserve` - reserve amount of buying asset
+/// - `in_reserve` - reserve amount of selling asset
+/// - `amount_out` - buy amount
+/// - `fee` - fee charged on the amount in
+///
+/// Returns MathError in case of error
+pub fn calculate_in_given_out_with_fee(
+    out_reserve: Balance,
+    in_reserve: Balance,
+    amount_out: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    if fee_numerator == 0 {
+        return calculate_in_given_out(out_reserve, in_reserve, amount_out);
+    }
+
+    ensure!(amount_out <= out_reserve, InsufficientOutReserve);
+
+    let (out_reserve_hp, in_reserve_hp, amount_out_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(out_reserve, in_reserve, amount_out, fee_numerator, fee_denominator);
+
+    let numerator = in_reserve_hp
+        .checked_mul(amount_out_hp).ok_or(Overflow)?
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?;
+    let denominator = (out_reserve_hp - amount_out_hp)
+        .checked_mul(fee_denominator_hp - fee_numerator_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+    let (buy_price_hp, remainder) = numerator.div_mod(denominator);
+
+    match remainder.is_zero() {
+        true => to_balance!(buy_price_hp),
+        false => round_up!(to_balance!(buy_price_hp)?),
+    }
+}
+
+/// Checks that a fee has a denominator and takes at most the whole amount.
+fn ensure_valid_fee((fee_numerator, fee_denominator): Fee) -> Result<(), MathError> {
+    ensure!(fee_denominator != 0 && fee_numerator <= fee_denominator, InvalidFee);
+    Ok(())
+}
+
+/// Calculating how to route a trade across pools and time slices to minimize total slippage.
+/// The trade is spread evenly across slices, assuming arbitrage restores the reserves between slices,
+/// and every slice is split across pools so that their marginal prices after the trade are equal.
+///
+/// - `pools` - (in reserve, out reserve) of every pool trading the pair
+/// - `total_amount_in` - total amount to be sold
+/// - `slices` - number of time slices
+/// - `fee` - fee charged by every pool
+///
+/// Returns amounts routed to each pool per slice, or MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn optimal_schedule(
+    pools: &[(Balance, Balance)],
+    total_amount_in: Balance,
+    slices: u32,
+    fee: Fee,
+) -> Result<Vec<Vec<Balance>>, MathError> {
+    ensure!(slices != 0, ZeroInReserve);
+
+    let slice_amount = total_amount_in / Balance::from(slices);
+    let remainder = total_amount_in % Balance::from(slices);
+
+    // The remainder is spread one unit per slice over the first slices, so the schedule adds up to the total.
+    // A nonzero remainder means at least two slices, so the larger slice amount cannot overflow.
+    let split = optimal_split(pools, slice_amount, fee)?;
+    let split_with_remainder = match remainder {
+        0 => split.clone(),
+        _ => optimal_split(pools, slice_amount + 1, fee)?,
+    };
+
+    Ok((0..slices)
+        .map(|slice| match Balance::from(slice) < remainder {
+            true => split_with_remainder.clone(),
+            false => split.clone(),
+        })
+        .collect())
+}
+
+/// Splits `amount_in` across pools so that all pools used end up at the same marginal price.
+/// Formula : AMOUNT_I = (SQRT(IN_I * OUT_I) * (G * AMOUNT + D * SUM(IN)) - IN_I * D * SUM(SQRT(IN * OUT))) / (G * SUM(SQRT(IN * OUT)))
+/// where D is the fee denominator, G is D minus the fee numerator and the sums run over pools receiving a share.
+#[cfg(feature = "alloc")]
+fn optimal_split(pools: &[(Balance, Balance)], amount_in: Balance, fee: Fee) -> Result<Vec<Balance>, MathError> {
+    ensure_valid_fee(fee)?;
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    let (amount_in_hp, fee_numerator_hp, fee_denominator_hp) = to_u256!(amount_in, fee_numerator, fee_denominator);
+    let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+
+    let depths = pools
+        .iter()
+        .map(|&(in_reserve, out_reserve)| {
+            let (in_reserve_hp, out_reserve_hp) = to_u256!(in_reserve, out_reserve);
+            in_reserve_hp.checked_mul(out_reserve_hp).map(integer_sqrt).ok_or(Overflow)
+        })
+        .collect::<Result<Vec<U256>, MathError>>()?;
+
+    let mut active: Vec<bool> = depths.iter().map(|depth| !depth.is_zero()).collect();
+    ensure!(active.contains(&true), ZeroInReserve);
+
+    if amount_in == 0 {
+        return Ok(vec![0; pools.len()]);
+    }
+
+    let mut amounts = vec![U256::zero(); pools.len()];
+
+    loop {
+        let mut in_reserve_sum = U256::zero();
+        let mut depth_sum = U256::zero();
+        for (i, &(in_reserve, _)) in pools.iter().enumerate().filter(|(i, _)| active[*i]) {
+            in_reserve_sum = in_reserve_sum.checked_add(U256::from(in_reserve)).ok_or(Overflow)?;
+            depth_sum = depth_sum.checked_add(depths[i]).ok_or(Overflow)?;
+        }
+
+        let target = fee_multiplier_hp
+            .checked_mul(amount_in_hp).ok_or(Overflow)?
+            .checked_add(fee_denominator_hp.checked_mul(in_reserve_sum).ok_or(Overflow)?).ok_or(Overflow)?;
+        let denominator = fee_multiplier_hp.checked_mul(depth_sum).ok_or(Overflow)?;
+
+        let mut deactivated = false;
+        for (i, &(in_reserve, _)) in pools.iter().enumerate() {
+            if !active[i] {
+                amounts[i] = U256::zero();
+                continue;
+            }
+
+            let gross = depths[i].checked_mul(target).ok_or(Overflow)?;
+            let offset = U256::from(in_reserve)
+                .checked_mul(fee_denominator_hp).ok_or(Overflow)?
+                .checked_mul(depth_sum).ok_or(Overflow)?;
+
+            match gross.checked_sub(offset) {
+                Some(numerator) if !numerator.is_zero() => {
+                    amounts[i] = numerator.checked_div(denominator).ok_or(Overflow)?;
+                }
+                _ => {
+                    active[i] = false;
+                    deactivated = true;
+                }
+            }
+        }
+
+        if !deactivated {
+            break;
+        }
+        ensure!(active.contains(&true), ZeroInReserve);
+    }
+
+    let mut result = amounts
+        .into_iter()
+        .map(|amount| Ok(to_balance!(amount)?.min(amount_in)))
+        .collect::<Result<Vec<Balance>, MathError>>()?;
+
+    // Flooring leaves a few units unallocated, route them to the pool taking the largest share.
+    let allocated = result.iter().try_fold(0, |acc: Balance, &amount| acc.checked_add(amount)).ok_or(Overflow)?;
+    let largest = (0..result.len()).filter(|&i| active[i]).max_by_key(|&i| result[i]).ok_or(ZeroInReserve)?;
+    result[largest] = result[largest]
+        .checked_add(amount_in.checked_sub(allocated).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    Ok(result)
+}
+
+/// Calculating the fee as realized on the output, scaled by 1e18.
+/// Because of the curve this differs from the nominal fee charged on the input, especially for large trades.
+/// A swap with no output realizes no fee.
+/// Formula : (OUT_WITHOUT_FEE - OUT_WITH_FEE) * 1e18 / OUT_WITHOUT_FEE
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn realized_fee_on_output(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let amount_out = calculate_out_given_in(in_reserve, out_reserve, amount_in)?;
+    let amount_out_with_fee = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+
+    if amount_out == 0 {
+        return to_balance!(0);
+    }
+
+    let (amount_out_hp, fee_amount_hp, fixed_one_hp) =
+        to_u256!(amount_out, amount_out.saturating_sub(amount_out_with_fee), FIXED_ONE);
+
+    let realized_fee_hp = fee_amount_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(amount_out_hp).ok_or(Overflow)?;
+
+    to_balance!(realized_fee_hp)
+}
+
+/// Calculating amount to be received from the pool given the amount to be sent to the pool and both reserves,
+/// rejecting results whose truncated remainder is too large relative to the result.
+/// Formula : OUT_RESERVE * AMOUNT_IN / (IN_RESERVE + AMOUNT_IN)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `max_relative_error_1e18` - largest accepted ratio of the dropped remainder to the result, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_precise(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    max_relative_error_1e18: Balance,
+) -> Result<Balance, MathError> {
+    let (in_reserve_hp, out_reserve_hp, amount_in_hp) = to_u256!(in_reserve, out_reserve, amount_in);
+
+    let denominator = in_reserve_hp.checked_add(amount_in_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let numerator = out_reserve_hp.checked_mul(amount_in_hp).ok_or(Overflow)?;
+    let (quotient, remainder) = numerator.div_mod(denominator);
+
+    if !remainder.is_zero() {
+        ensure!(!quotient.is_zero(), PrecisionLoss);
+
+        let (max_relative_error_hp, fixed_one_hp) = to_u256!(max_relative_error_1e18, FIXED_ONE);
+
+        let error_hp = remainder
+            .checked_mul(fixed_one_hp).ok_or(Overflow)?
+            .checked_div(denominator).ok_or(Overflow)?;
+        let allowed_hp = max_relative_error_hp.checked_mul(quotient).ok_or(Overflow)?;
+        ensure!(error_hp <= allowed_hp, PrecisionLoss);
+    }
+
+    to_balance!(quotient)
+}
+
+/// Calculating virtual reserves equivalent to a Uniswap v3 style sqrt price and liquidity.
+/// Both reserves are rounded down, so each is at most one unit below the exact value.
+/// Formula A: LIQUIDITY * 2^96 / SQRT_PRICE_X96
+/// Formula B: LIQUIDITY * SQRT_PRICE_X96 / 2^96
+///
+/// - `sqrt_price_x96` - square root of the price of asset a in asset b, as Q64.96 fixed point
+/// - `liquidity` - liquidity of the position
+///
+/// Returns MathError in case of error
+pub fn reserves_from_sqrt_price(sqrt_price_x96: U256, liquidity: U256) -> Result<(Balance, Balance), MathError> {
+    ensure!(!sqrt_price_x96.is_zero(), ZeroInReserve);
+
+    let reserve_a_hp = liquidity
+        .checked_mul(U256::one() << Q96_SHIFT).ok_or(Overflow)?
+        .checked_div(sqrt_price_x96).ok_or(Overflow)?;
+
+    let reserve_b_hp = liquidity.checked_mul(sqrt_price_x96).ok_or(Overflow)? >> Q96_SHIFT;
+
+    Ok((to_balance!(reserve_a_hp)?, to_balance!(reserve_b_hp)?))
+}
+
+/// Calculating the largest amount that can be sold to the pool before its spot price drops to the target price.
+/// The fee is deducted from the amount before it moves the price.
+/// Formula : (SQRT(IN_RESERVE * OUT_RESERVE * 1e18 / TARGET_PRICE) - IN_RESERVE) * FEE_DEN / (FEE_DEN - FEE_NUM)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `target_price_1e18` - spot price of selling asset in buying asset to stop at, scaled by 1e18
+/// - `fee` - fee charged on the amount in
+///
+/// Returns MathError in case of error
+pub fn max_amount_in_for_target_price(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    target_price_1e18: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(in_reserve != 0, ZeroInReserve);
+    ensure!(target_price_1e18 != 0, ZeroInReserve);
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    let (in_reserve_hp, out_reserve_hp, target_price_hp, fixed_one_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(in_reserve, out_reserve, target_price_1e18, FIXED_ONE, fee_numerator, fee_denominator);
+
+    let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+
+    let scaled_k_hp = in_reserve_hp
+        .checked_mul(out_reserve_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?;
+
+    let target_in_reserve_hp = integer_sqrt(scaled_k_hp.checked_div(target_price_hp).ok_or(Overflow)?);
+
+    if target_in_reserve_hp <= in_reserve_hp {
+        return Ok(0);
+    }
+
+    let amount_in_hp = (target_in_reserve_hp - in_reserve_hp)
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?
+        .checked_div(fee_multiplier_hp).ok_or(Overflow)?;
+
+    to_balance!(amount_in_hp)
+}
+
+/// Calculating amount to be sold to the pool to raise the marginal price of buying asset by given basis points.
+/// Formula : MAX_AMOUNT_IN_FOR_TARGET_PRICE(OUT_RESERVE * 1e18 * 10000 / (IN_RESERVE * (10000 + BPS)))
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `bps` - price move in basis points
+/// - `fee` - fee charged on the amount in
+///
+/// Returns MathError in case of error
+pub fn cost_to_move_price_bps(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    bps: u16,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    ensure!(in_reserve != 0, ZeroInReserve);
+
+    let (in_reserve_hp, out_reserve_hp, fixed_one_hp, bps_hp, bps_one_hp) =
+        to_u256!(in_reserve, out_reserve, FIXED_ONE, bps, BPS_ONE);
+
+    let target_price_hp = out_reserve_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_mul(bps_one_hp).ok_or(Overflow)?
+        .checked_div(in_reserve_hp.checked_mul(bps_one_hp + bps_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    max_amount_in_for_target_price(in_reserve, out_reserve, to_balance!(target_price_hp)?, fee)
+}
+
+/// Calculating the underlying amounts of an LP position after the pool rebalances to a new price at constant k.
+/// Formula A: ASSET_A_RESERVE * 1e18 / SQRT(PRICE_MOVE * 1e18) * SHARES / TOTAL_SHARES
+/// Formula B: ASSET_B_RESERVE * SQRT(PRICE_MOVE * 1e18) / 1e18 * SHARES / TOTAL_SHARES
+///
+/// - `initial_reserve_a` - reserve amount of asset a before the move
+/// - `initial_reserve_b` - reserve amount of asset b before the move
+/// - `shares` - shares of the position
+/// - `total_shares` - total shares of the pool
+/// - `price_move_1e18` - ratio of the new to the old price of asset a in asset b, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn position_value_after_price_move(
+    initial_reserve_a: Balance,
+    initial_reserve_b: Balance,
+    shares: Balance,
+    total_shares: Balance,
+    price_move_1e18: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    ensure!(price_move_1e18 != 0, ZeroInReserve);
+
+    let (a_reserve_hp, b_reserve_hp, price_move_hp, fixed_one_hp) =
+        to_u256!(initial_reserve_a, initial_reserve_b, price_move_1e18, FIXED_ONE);
+
+    let sqrt_move_hp = integer_sqrt(price_move_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?);
+
+    let new_a_reserve_hp = a_reserve_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(sqrt_move_hp).ok_or(Overflow)?;
+
+    let new_b_reserve_hp = b_reserve_hp
+        .checked_mul(sqrt_move_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp).ok_or(Overflow)?;
+
+    calculate_liquidity_out(to_balance!(new_a_reserve_hp)?, to_balance!(new_b_reserve_hp)?, shares, total_shares)
+}
+
+/// Calculating amount to be received from the pool given the amount to be sent to the pool and both reserves,
+/// failing instead of rounding when the division is not exact.
+/// Formula : OUT_RESERVE * AMOUNT_IN / (IN_RESERVE + AMOUNT_IN)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_exact_only(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+) -> Result<Balance, MathError> {
+    let (in_reserve_hp, out_reserve_hp, amount_in_hp) = to_u256!(in_reserve, out_reserve, amount_in);
+
+    let denominator = in_reserve_hp.checked_add(amount_in_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let numerator = out_reserve_hp.checked_mul(amount_in_hp).ok_or(Overflow)?;
+    let (sale_price_hp, remainder) = numerator.div_mod(denominator);
+    ensure!(remainder.is_zero(), Inexact);
+
+    to_balance!(sale_price_hp)
+}
+
+/// Calculating the largest amount of shares that can be burned while keeping both reserves at or above a minimum.
+/// Takes into account that withdrawn amounts are rounded down by `calculate_liquidity_out`.
+/// Formula : MIN(((RESERVE - MIN_RESERVE + 1) * TOTAL_SHARES - 1) / RESERVE) for each asset, capped at TOTAL_SHARES
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `total_shares` - total shares of the pool
+/// - `min_reserve` - minimum amount of each asset to be left in the pool
+///
+/// Returns MathError in case of error
+pub fn max_burnable_shares(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    total_shares: Balance,
+    min_reserve: Balance,
+) -> Result<Balance, MathError> {
+    ensure!(total_shares != 0, ZeroInReserve);
+
+    let max_shares_a = max_burnable_shares_for_reserve(reserve_a, total_shares, min_reserve)?;
+    let max_shares_b = max_burnable_shares_for_reserve(reserve_b, total_shares, min_reserve)?;
+
+    Ok(max_shares_a.min(max_shares_b))
+}
+
+fn max_burnable_shares_for_reserve(
+    reserve: Balance,
+    total_shares: Balance,
+    min_reserve: Balance,
+) -> Result<Balance, MathError> {
+    if min_reserve > reserve {
+        return Ok(0);
+    }
+    if reserve == 0 {
+        return Ok(total_shares);
+    }
+
+    let (reserve_hp, total_shares_hp) = to_u256!(reserve, total_shares);
+
+    let max_shares_hp = U256::from(reserve - min_reserve)
+        .checked_add(U256::one()).ok_or(Overflow)?
+        .checked_mul(total_shares_hp).ok_or(Overflow)?
+        .checked_sub(U256::one()).ok_or(Overflow)?
+        .checked_div(reserve_hp).ok_or(Overflow)?;
+
+    to_balance!(max_shares_hp.min(total_shares_hp))
+}
+
+/// Calculating the harmonic mean of prices, the blended price of an amount split evenly across pools.
+/// Formula : N / SUM(1 / PRICE)
+///
+/// - `prices_1e18` - prices to blend, scaled by 1e18
+///
+/// Returns MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn harmonic_mean_price(prices_1e18: &[Balance]) -> Result<Balance, MathError> {
+    ensure!(!prices_1e18.is_empty(), ZeroInReserve);
+
+    // Reciprocals are taken against 2^192 so that even u128::MAX keeps 64 bits of precision.
+    let scale_hp = U256::one() << RECIPROCAL_SHIFT;
+
+    let reciprocal_sum_hp = prices_1e18.iter().try_fold(U256::zero(), |acc, &price| {
+        ensure!(price != 0, ZeroInReserve);
+        acc.checked_add(scale_hp / U256::from(price)).ok_or(Overflow)
+    })?;
+
+    let mean_hp = U256::from(prices_1e18.len())
+        .checked_mul(scale_hp).ok_or(Overflow)?
+        .checked_div(reciprocal_sum_hp).ok_or(Overflow)?;
+
+    // Rounding the reciprocals down can push the mean above the highest price, which the exact mean never exceeds.
+    let max_price_hp = U256::from(prices_1e18.iter().copied().max().unwrap_or_default());
+
+    to_balance!(mean_hp.min(max_price_hp))
+}
+
+/// Calculating the amount of asset b an LP has to short to be neutral to the price of asset b.
+/// After arbitrage moves the pool to PRICE, the position holds SQRT(K / PRICE) * SHARES / TOTAL_SHARES of asset b,
+/// which is also the derivative of the position value in asset a with respect to the price. The holding is never
+/// negative, so the LP is always long asset b and the hedge is always a short: it does not change sign as the price
+/// moves, it grows as the price falls and shrinks as it rises.
+/// Formula : SQRT(ASSET_A_RESERVE * ASSET_B_RESERVE * 1e18 / PRICE) * SHARES / TOTAL_SHARES
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `shares` - shares of the position
+/// - `total_shares` - total shares of the pool
+/// - `price_1e18` - price of asset b in asset a, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn delta_neutral_hedge(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    shares: Balance,
+    total_shares: Balance,
+    price_1e18: Balance,
+) -> Result<Balance, MathError> {
+    ensure!(total_shares != 0 && price_1e18 != 0, ZeroInReserve);
+
+    let (reserve_a_hp, reserve_b_hp, shares_hp, total_shares_hp, price_hp, fixed_one_hp) =
+        to_u256!(reserve_a, reserve_b, shares, total_shares, price_1e18, FIXED_ONE);
+
+    let pool_b_hp = integer_sqrt(reserve_a_hp
+        .checked_mul(reserve_b_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(price_hp).ok_or(Overflow)?);
+
+    let hedge_hp = pool_b_hp
+        .checked_mul(shares_hp).ok_or(Overflow)?
+        .checked_div(total_shares_hp).ok_or(Overflow)?;
+
+    to_balance!(hedge_hp)
+}
+
+/// Calculating the fees earned by an LP from a fee growth accumulator over a period.
+/// Formula : (GROWTH_END - GROWTH_START) * SHARES / 1e18
+///
+/// - `growth_start` - fee growth per share at the start of the period, scaled by 1e18
+/// - `growth_end` - fee growth per share at the end of the period, scaled by 1e18
+/// - `shares` - shares of the LP
+///
+/// Returns MathError in case of error
+pub fn lp_fees_earned(growth_start: U256, growth_end: U256, shares: Balance) -> Result<Balance, MathError> {
+    if growth_end <= growth_start {
+        return Ok(0);
+    }
+
+    let (shares_hp, fixed_one_hp) = to_u256!(shares, FIXED_ONE);
+
+    let fees_hp = (growth_end - growth_start)
+        .checked_mul(shares_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp).ok_or(Overflow)?;
+
+    to_balance!(fees_hp)
+}
+
+/// Returns the most extreme reserve ratio considered safe, as (smaller reserve, larger reserve).
+/// Swaps multiply OUT_RESERVE * AMOUNT_IN * (FEE_DEN - FEE_NUM) in U256, which a single unit against Balance::MAX
+/// stays far below, so no ratio overflows on its own. Safety is bounded by the product of the reserves, see `is_ratio_safe`.
+pub fn max_safe_reserve_ratio() -> (Balance, Balance) {
+    (1, Balance::MAX)
+}
+
+/// Checks whether reserves are within the safe envelope for swapping.
+/// Reserves are safe when they are nonzero and selling up to a whole reserve with any fee cannot overflow
+/// `calculate_out_given_in_with_fee`, that is when RESERVE_A * RESERVE_B * (u32::MAX - 1) fits in U256,
+/// u32::MAX - 1 being the largest FEE_DEN - FEE_NUM of a nonzero fee.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+pub fn is_ratio_safe(reserve_a: Balance, reserve_b: Balance) -> bool {
+    if reserve_a == 0 || reserve_b == 0 {
+        return false;
+    }
+
+    let (reserve_a_hp, reserve_b_hp, max_fee_multiplier_hp) = to_u256!(reserve_a, reserve_b, u32::MAX - 1);
+
+    reserve_a_hp
+        .checked_mul(reserve_b_hp)
+        .and_then(|k| k.checked_mul(max_fee_multiplier_hp))
+        .is_some()
+}
+
+/// Calculating amount to be sold to the pool to raise the price of buying asset to the given level.
+/// Formula : SQRT(IN_RESERVE * OUT_RESERVE * PRICE_LEVEL / 1e18) - IN_RESERVE
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `price_level_1e18` - price of buying asset in selling asset to reach, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn depth_to_price(in_reserve: Balance, out_reserve: Balance, price_level_1e18: Balance) -> Result<Balance, MathError> {
+    let (in_reserve_hp, out_reserve_hp, price_level_hp, fixed_one_hp) =
+        to_u256!(in_reserve, out_reserve, price_level_1e18, FIXED_ONE);
+
+    let target_in_reserve_hp = integer_sqrt(
+        in_reserve_hp
+            .checked_mul(out_reserve_hp).ok_or(Overflow)?
+            .checked_mul(price_level_hp).ok_or(Overflow)?
+            .checked_div(fixed_one_hp).ok_or(Overflow)?,
+    );
+
+    to_balance!(target_in_reserve_hp.saturating_sub(in_reserve_hp))
+}
+
+/// Calculating depth chart data, the cumulative amount the pool absorbs before reaching each price level.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `price_levels` - prices of buying asset in selling asset, scaled by 1e18
+///
+/// Returns amounts for each price level, or MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn depth_chart(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    price_levels: &[Balance],
+) -> Result<Vec<Balance>, MathError> {
+    price_levels
+        .iter()
+        .map(|&price_level| depth_to_price(in_reserve, out_reserve, price_level))
+        .collect()
+}
+
+/// Calculating the all-in price of a swap including fee and gas, as value spent per buying asset received, scaled by 1e18.
+/// The amount in is valued in buying asset at the current spot price.
+/// Formula : (SPOT_PRICE(AMOUNT_IN) + GAS_COST) * 1e18 / AMOUNT_OUT_WITH_FEE
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+/// - `gas_cost_in_out_asset` - gas cost of the swap, expressed in buying asset
+///
+/// Returns MathError in case of error
+pub fn effective_price_with_gas(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+    gas_cost_in_out_asset: Balance,
+) -> Result<Balance, MathError> {
+    let amount_in_valued = calculate_spot_price(in_reserve, out_reserve, amount_in)?;
+    let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+    ensure!(amount_out != 0, ZeroInReserve);
+
+    let (amount_in_valued_hp, gas_cost_hp, amount_out_hp, fixed_one_hp) =
+        to_u256!(amount_in_valued, gas_cost_in_out_asset, amount_out, FIXED_ONE);
+
+    let price_hp = amount_in_valued_hp
+        .checked_add(gas_cost_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(amount_out_hp).ok_or(Overflow)?;
+
+    to_balance!(price_hp)
+}
+
+/// Checks whether a pool holds enough liquidity to be worth routing through.
+/// A pool is routable when both reserves are at least `min_liquidity` and SQRT(RESERVE_A * RESERVE_B)
+/// is at least 1000, the depth below which swaps are dominated by rounding.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `min_liquidity` - minimum amount of each asset in the pool
+pub fn is_pool_routable(reserve_a: Balance, reserve_b: Balance, min_liquidity: Balance) -> bool {
+    if reserve_a < min_liquidity || reserve_b < min_liquidity {
+        return false;
+    }
+
+    let (reserve_a_hp, reserve_b_hp) = to_u256!(reserve_a, reserve_b);
+
+    integer_sqrt(reserve_a_hp * reserve_b_hp) >= U256::from(MIN_ROUTABLE_DEPTH)
+}
+
+/// Calculating the largest swap within `max_amount_in` whose average price does not fall below `min_price_1e18`.
+/// No amount above the crossing of the unfloored average price with the limit qualifies. Below it the floored output
+/// makes the average price a sawtooth, so amounts are scanned down from the crossing for `PARTIAL_FILL_SCAN` units,
+/// and beyond that the largest qualifying amount found by bisection is returned.
+/// Formula : CROSSING = (OUT_RESERVE * G * 1e18 - MIN_PRICE * IN_RESERVE * D) / (MIN_PRICE * G)
+/// where D is the fee denominator and G is D minus the fee numerator.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `max_amount_in` - largest amount to be sold
+/// - `min_price_1e18` - lowest accepted average price of selling asset in buying asset, scaled by 1e18
+/// - `fee` - fee charged on the amount in
+///
+/// Returns (amount in used, amount out), or MathError in case of error
+pub fn partial_fill_out(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    max_amount_in: Balance,
+    min_price_1e18: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(in_reserve != 0, ZeroInReserve);
+
+    let (fee_numerator, fee_denominator) = fee;
+    let (in_reserve_hp, out_reserve_hp, min_price_hp, fixed_one_hp, fee_multiplier_hp, fee_denominator_hp) =
+        to_u256!(in_reserve, out_reserve, min_price_1e18, FIXED_ONE, fee_denominator - fee_numerator, fee_denominator);
+
+    let within_price = |amount_in: Balance| -> Result<Option<Balance>, MathError> {
+        let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+        let value_out_hp = U256::from(amount_out).checked_mul(fixed_one_hp).ok_or(Overflow)?;
+        let value_min_hp = U256::from(amount_in).checked_mul(min_price_hp).ok_or(Overflow)?;
+        Ok(match value_out_hp >= value_min_hp {
+            true => Some(amount_out),
+            false => None,
+        })
+    };
+
+    if max_amount_in == 0 {
+        return Ok((0, 0));
+    }
+    if min_price_1e18 == 0 {
+        let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, max_amount_in, fee)?;
+        return Ok((max_amount_in, amount_out));
+    }
+
+    let spot_value_hp = out_reserve_hp
+        .checked_mul(fee_multiplier_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?;
+    let limit_value_hp = min_price_hp
+        .checked_mul(in_reserve_hp).ok_or(Overflow)?
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?;
+    if spot_value_hp <= limit_value_hp {
+        return Ok((0, 0));
+    }
+
+    let crossing_hp = (spot_value_hp - limit_value_hp)
+        .checked_div(min_price_hp.checked_mul(fee_multiplier_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+    let crossing = match crossing_hp > U256::from(max_amount_in) {
+        true => max_amount_in,
+        false => to_balance!(crossing_hp)?,
+    };
+
+    let scan_end = crossing.saturating_sub(PARTIAL_FILL_SCAN);
+    for amount_in in (scan_end..=crossing).rev() {
+        if let Some(amount_out) = within_price(amount_in)? {
+            return Ok((amount_in, amount_out));
+        }
+    }
+
+    let (mut low, mut high) = (0, scan_end.saturating_sub(1));
+    while low < high {
+        let mid = high - (high - low) / 2;
+        match within_price(mid)? {
+            Some(_) => low = mid,
+            None => high = mid - 1,
+        }
+    }
+
+    if low == 0 {
+        return Ok((0, 0));
+    }
+
+    let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, low, fee)?;
+    Ok((low, amount_out))
+}
+
+/// Calculating reserves after tokens are transferred directly into the pool.
+/// No shares are minted, so no LP is diluted and the value of every existing share rises.
+/// Formula A: ASSET_A_RESERVE + DONATE_A
+/// Formula B: ASSET_B_RESERVE + DONATE_B
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `donate_a` - amount of asset a donated
+/// - `donate_b` - amount of asset b donated
+///
+/// Returns MathError in case of error
+pub fn reserves_after_donation(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    donate_a: Balance,
+    donate_b: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    let new_reserve_a = reserve_a.checked_add(donate_a).ok_or(Overflow)?;
+    let new_reserve_b = reserve_b.checked_add(donate_b).ok_or(Overflow)?;
+
+    Ok((new_reserve_a, new_reserve_b))
+}
+
+/// Calculating price impact of a swap, the shortfall of the execution price against the spot price, scaled by 1e18.
+/// Formula : AMOUNT_IN * 1e18 / (IN_RESERVE + AMOUNT_IN)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `amount_in` - amount
+///
+/// Returns MathError in case of error
+pub fn calculate_price_impact(in_reserve: Balance, amount_in: Balance) -> Result<Balance, MathError> {
+    let (in_reserve_hp, amount_in_hp, fixed_one_hp) = to_u256!(in_reserve, amount_in, FIXED_ONE);
+
+    let denominator = in_reserve_hp.checked_add(amount_in_hp).ok_or(Overflow)?;
+    ensure!(!denominator.is_zero(), ZeroInReserve);
+
+    let price_impact_hp = amount_in_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(denominator).ok_or(Overflow)?;
+
+    to_balance!(price_impact_hp)
+}
+
+/// Calculating amount to be received from the pool together with the price impact of the swap.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns (amount out, price impact scaled by 1e18), or MathError in case of error
+pub fn net_quote(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+    let price_impact = calculate_price_impact(in_reserve, amount_in)?;
+
+    Ok((amount_out, price_impact))
+}
+
+/// Calculating price of asset a in asset b from reserves, scaled by 1e18.
+/// Formula : ASSET_B_RESERVE * 1e18 / ASSET_A_RESERVE
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+///
+/// Returns MathError in case of error
+pub fn price_from_reserves(reserve_a: Balance, reserve_b: Balance) -> Result<Balance, MathError> {
+    calculate_spot_price(reserve_a, reserve_b, FIXED_ONE)
+}
+
+/// Calculating reserves to seed a new pool with, so that its price matches an oracle price.
+/// Both sides are worth `target_value_a`, making the total value of the pool twice that.
+/// Formula A: TARGET_VALUE_A
+/// Formula B: TARGET_VALUE_A * 1e18 / ORACLE_PRICE
+///
+/// - `target_value_a` - value of each side of the pool in asset a
+/// - `oracle_price_b_in_a_1e18` - price of asset b in asset a, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn seed_reserves(target_value_a: Balance, oracle_price_b_in_a_1e18: Balance) -> Result<(Balance, Balance), MathError> {
+    ensure!(oracle_price_b_in_a_1e18 != 0, ZeroInReserve);
+
+    let (target_value_hp, oracle_price_hp, fixed_one_hp) = to_u256!(target_value_a, oracle_price_b_in_a_1e18, FIXED_ONE);
+
+    let reserve_b_hp = target_value_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(oracle_price_hp).ok_or(Overflow)?;
+
+    Ok((target_value_a, to_balance!(reserve_b_hp)?))
+}
+
+/// Calculating shares minted for a deposit into a pool whose price may have drifted from the deposit ratio.
+/// The smaller of the asset a and asset b based amounts is minted, so over-supplying one asset mints no extra shares.
+/// Formula : MIN(AMOUNT_A * TOTAL_SHARES / ASSET_A_RESERVE, AMOUNT_B * TOTAL_SHARES / ASSET_B_RESERVE)
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `amount_a` - deposited amount of asset a
+/// - `amount_b` - deposited amount of asset b
+/// - `total_shares` - total shares of the pool
+///
+/// Returns MathError in case of error
+pub fn calculate_shares_minted_safe(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    amount_a: Balance,
+    amount_b: Balance,
+    total_shares: Balance,
+) -> Result<Balance, MathError> {
+    ensure!(reserve_a != 0 && reserve_b != 0, ZeroInReserve);
+
+    let (a_reserve_hp, b_reserve_hp, amount_a_hp, amount_b_hp, shares_hp) =
+        to_u256!(reserve_a, reserve_b, amount_a, amount_b, total_shares);
+
+    let shares_a_hp = amount_a_hp
+        .checked_mul(shares_hp).ok_or(Overflow)?
+        .checked_div(a_reserve_hp).ok_or(Overflow)?;
+
+    let shares_b_hp = amount_b_hp
+        .checked_mul(shares_hp).ok_or(Overflow)?
+        .checked_div(b_reserve_hp).ok_or(Overflow)?;
+
+    to_balance!(shares_a_hp.min(shares_b_hp))
+}
+
+/// Calculating shares minted for the first deposit into an empty pool.
+/// The result is rounded down.
+/// Formula : SQRT(AMOUNT_A * AMOUNT_B)
+///
+/// - `amount_a` - deposited amount of asset a
+/// - `amount_b` - deposited amount of asset b
+///
+/// Returns MathError in case of error
+pub fn calculate_initial_shares(amount_a: Balance, amount_b: Balance) -> Result<Balance, MathError> {
+    let (amount_a_hp, amount_b_hp) = to_u256!(amount_a, amount_b);
+
+    let product_hp = amount_a_hp.checked_mul(amount_b_hp).ok_or(Overflow)?;
+
+    to_balance!(integer_sqrt(product_hp))
+}
+
+/// Calculating pool utilization, the traded volume over a window relative to liquidity, scaled by 1e18.
+/// Formula : VOLUME_WINDOW * 1e18 / RESERVE
+///
+/// - `volume_window` - amount traded during the window
+/// - `reserve` - reserve amount of the traded asset
+///
+/// Returns MathError in case of error
+pub fn pool_utilization(volume_window: Balance, reserve: Balance) -> Result<Balance, MathError> {
+    ensure!(reserve != 0, ZeroInReserve);
+
+    let (volume_hp, reserve_hp, fixed_one_hp) = to_u256!(volume_window, reserve, FIXED_ONE);
+
+    let utilization_hp = volume_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(reserve_hp).ok_or(Overflow)?;
+
+    to_balance!(utilization_hp)
+}
+
+/// Calculating how to split a trade between two pools of the same pair with different fees to maximize output.
+/// The split equalizes marginal outputs of both pools after the trade, falling back to a single pool when better.
+/// Formula : TO_LOW = (S_LOW * (IN_HIGH * D_HIGH + G_HIGH * AMOUNT_IN) * D_LOW - S_HIGH * IN_LOW * D_LOW * D_HIGH)
+///                    / (S_LOW * G_HIGH * D_LOW + S_HIGH * G_LOW * D_HIGH)
+/// where D is the fee denominator, G is D minus the fee numerator and S is SQRT(G * IN * OUT / D) of each pool.
+///
+/// - `tier_low` - (in reserve, out reserve, fee) of the lower fee pool
+/// - `tier_high` - (in reserve, out reserve, fee) of the higher fee pool
+/// - `amount_in` - amount to be sold
+///
+/// Returns (amount to lower fee pool, amount to higher fee pool, total amount out), or MathError in case of error
+pub fn best_split_across_tiers(
+    tier_low: (Balance, Balance, Fee),
+    tier_high: (Balance, Balance, Fee),
+    amount_in: Balance,
+) -> Result<(Balance, Balance, Balance), MathError> {
+    let (in_low, out_low, fee_low) = tier_low;
+    let (in_high, out_high, fee_high) = tier_high;
+    ensure_valid_fee(fee_low)?;
+    ensure_valid_fee(fee_high)?;
+
+    let amount_out = |in_reserve, out_reserve, amount, fee| match amount {
+        0 => Ok(0),
+        _ => calculate_out_given_in_with_fee(in_reserve, out_reserve, amount, fee),
+    };
+    let total_out = |to_low: Balance| -> Result<Balance, MathError> {
+        amount_out(in_low, out_low, to_low, fee_low)?
+            .checked_add(amount_out(in_high, out_high, amount_in - to_low, fee_high)?).ok_or(Overflow)
+    };
+
+    let depth = |in_reserve: Balance, out_reserve: Balance, (fee_numerator, fee_denominator): Fee| {
+        let (in_reserve_hp, out_reserve_hp, fee_numerator_hp, fee_denominator_hp) =
+            to_u256!(in_reserve, out_reserve, fee_numerator, fee_denominator);
+        let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+        let depth_hp = in_reserve_hp
+            .checked_mul(out_reserve_hp).ok_or(Overflow)?
+            .checked_mul(fee_multiplier_hp).ok_or(Overflow)?
+            .checked_div(fee_denominator_hp).ok_or(Overflow)?;
+        Ok((integer_sqrt(depth_hp), fee_multiplier_hp, fee_denominator_hp))
+    };
+
+    let (depth_low, multiplier_low, denominator_low) = depth(in_low, out_low, fee_low)?;
+    let (depth_high, multiplier_high, denominator_high) = depth(in_high, out_high, fee_high)?;
+    let (in_low_hp, in_high_hp, amount_in_hp) = to_u256!(in_low, in_high, amount_in);
+
+    let gross = depth_low
+        .checked_mul(
+            in_high_hp
+                .checked_mul(denominator_high).ok_or(Overflow)?
+                .checked_add(multiplier_high.checked_mul(amount_in_hp).ok_or(Overflow)?).ok_or(Overflow)?,
+        ).ok_or(Overflow)?
+        .checked_mul(denominator_low).ok_or(Overflow)?;
+    let offset = depth_high
+        .checked_mul(in_low_hp).ok_or(Overflow)?
+        .checked_mul(denominator_low).ok_or(Overflow)?
+        .checked_mul(denominator_high).ok_or(Overflow)?;
+    let denominator = depth_low
+        .checked_mul(multiplier_high).ok_or(Overflow)?
+        .checked_mul(denominator_low).ok_or(Overflow)?
+        .checked_add(
+            depth_high
+                .checked_mul(multiplier_low).ok_or(Overflow)?
+                .checked_mul(denominator_high).ok_or(Overflow)?,
+        ).ok_or(Overflow)?;
+
+    let split_to_low = match (gross.checked_sub(offset), denominator.is_zero()) {
+        (Some(numerator), false) => to_balance!(numerator.checked_div(denominator).ok_or(Overflow)?.min(amount_in_hp))?,
+        (None, false) => 0,
+        (_, true) => return Err(ZeroInReserve),
+    };
+
+    let mut best = (split_to_low, total_out(split_to_low)?);
+    for &to_low in [0, amount_in].iter() {
+        if let Ok(out) = total_out(to_low) {
+            if out > best.1 {
+                best = (to_low, out);
+            }
+        }
+    }
+
+    Ok((best.0, amount_in - best.0, best.1))
+}
+
+/// Calculating amount reduced by a slippage tolerance, rounded down.
+/// Formula : AMOUNT * (SLIPPAGE_DEN - SLIPPAGE_NUM) / SLIPPAGE_DEN
+///
+/// - `amount` - amount
+/// - `slippage` - slippage tolerance
+///
+/// Returns MathError in case of error
+pub fn apply_slippage_down(amount: Balance, slippage: Fee) -> Result<Balance, MathError> {
+    ensure_valid_fee(slippage)?;
+
+    let (slippage_numerator, slippage_denominator) = slippage;
+
+    let (amount_hp, numerator_hp, denominator_hp) = to_u256!(amount, slippage_numerator, slippage_denominator);
+
+    let reduced_hp = amount_hp
+        .checked_mul(denominator_hp - numerator_hp).ok_or(Overflow)?
+        .checked_div(denominator_hp).ok_or(Overflow)?;
+
+    to_balance!(reduced_hp)
+}
+
+/// Calculating total amount received from a trade executed in equal time slices.
+/// Assumes arbitrage restores the reserves between slices, so every slice trades against the given reserves.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `total_amount_in` - total amount to be sold
+/// - `slices` - number of time slices
+/// - `fee` - fee charged on every slice
+///
+/// Returns MathError in case of error
+pub fn twap_execution_output(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    total_amount_in: Balance,
+    slices: u32,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    twap_slice_outputs(in_reserve, out_reserve, total_amount_in, slices, fee, Ok)
+}
+
+/// Calculating the conservative minimum received from a trade executed in equal time slices,
+/// with the slippage tolerance applied to every slice.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `total_amount_in` - total amount to be sold
+/// - `slices` - number of time slices
+/// - `per_slice_slippage` - slippage tolerance of every slice
+/// - `fee` - fee charged on every slice
+///
+/// Returns MathError in case of error
+pub fn twap_min_received(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    total_amount_in: Balance,
+    slices: u32,
+    per_slice_slippage: Fee,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    twap_slice_outputs(in_reserve, out_reserve, total_amount_in, slices, fee, |amount_out| {
+        apply_slippage_down(amount_out, per_slice_slippage)
+    })
+}
+
+/// Sums the output of every slice after applying `adjust`.
+/// The remainder of the division into slices goes one unit each to the first slices.
+fn twap_slice_outputs(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    total_amount_in: Balance,
+    slices: u32,
+    fee: Fee,
+    adjust: impl Fn(Balance) -> Result<Balance, MathError>,
+) -> Result<Balance, MathError> {
+    ensure!(slices != 0, ZeroInReserve);
+
+    let slice_amount = total_amount_in / Balance::from(slices);
+    let remainder = total_amount_in % Balance::from(slices);
+
+    let slice_out = |amount_in: Balance| match amount_in {
+        0 => Ok(0),
+        _ => adjust(calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?),
+    };
+
+    // A nonzero remainder means at least two slices, so the larger slice amount cannot overflow.
+    let larger_out = match remainder {
+        0 => 0,
+        _ => slice_out(slice_amount + 1)?,
+    };
+    let smaller_out = slice_out(slice_amount)?;
+
+    let (larger_out_hp, smaller_out_hp, remainder_hp, rest_hp) =
+        to_u256!(larger_out, smaller_out, remainder, Balance::from(slices) - remainder);
+
+    let total_out_hp = larger_out_hp
+        .checked_mul(remainder_hp).ok_or(Overflow)?
+        .checked_add(smaller_out_hp.checked_mul(rest_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    to_balance!(total_out_hp)
+}
+
+/// Calculating the swap that brings the spot price of the pool to an external price.
+/// The fee stays in the pool, so the amount is the largest one not pushing the price past the external price,
+/// found by bisection below `max_amount_in_for_target_price`.
+///
+/// - `reserve_in` - reserve amount of asset in
+/// - `reserve_out` - reserve amount of asset out
+/// - `external_price_1e18` - external price of asset in in asset out, scaled by 1e18
+/// - `fee` - fee charged on the amount sold
+///
+/// Returns direction and amount to be sold, or MathError in case of error
+pub fn arbitrage_amount(
+    reserve_in: Balance,
+    reserve_out: Balance,
+    external_price_1e18: Balance,
+    fee: Fee,
+) -> Result<(SwapDirection, Balance), MathError> {
+    ensure!(reserve_in != 0 && reserve_out != 0, ZeroInReserve);
+    ensure!(external_price_1e18 != 0, ZeroInReserve);
+
+    let (reserve_in_hp, reserve_out_hp, external_price_hp, fixed_one_hp) =
+        to_u256!(reserve_in, reserve_out, external_price_1e18, FIXED_ONE);
+
+    let pool_value_hp = reserve_out_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?;
+    let external_value_hp = reserve_in_hp.checked_mul(external_price_hp).ok_or(Overflow)?;
+
+    if pool_value_hp >= external_value_hp {
+        let amount = max_amount_in_to_price_after_swap(reserve_in, reserve_out, external_price_1e18, fee)?;
+        Ok((SwapDirection::Sell, amount))
+    } else {
+        let inverse_price_hp = fixed_one_hp
+            .checked_mul(fixed_one_hp).ok_or(Overflow)?
+            .checked_div(external_price_hp).ok_or(Overflow)?;
+        ensure!(!inverse_price_hp.is_zero(), Overflow);
+
+        let amount = max_amount_in_to_price_after_swap(reserve_out, reserve_in, to_balance!(inverse_price_hp)?, fee)?;
+        Ok((SwapDirection::Buy, amount))
+    }
+}
+
+/// Largest amount in whose swap keeps (OUT_RESERVE - AMOUNT_OUT) * 1e18 / (IN_RESERVE + AMOUNT_IN) at or above the target.
+fn max_amount_in_to_price_after_swap(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    target_price_1e18: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let (target_price_hp, fixed_one_hp) = to_u256!(target_price_1e18, FIXED_ONE);
+
+    let above_target = |amount_in: Balance| -> Result<bool, MathError> {
+        let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+        let (new_in_hp, new_out_hp) = to_u256!(
+            in_reserve.checked_add(amount_in).ok_or(Overflow)?,
+            out_reserve.saturating_sub(amount_out)
+        );
+        Ok(new_out_hp.checked_mul(fixed_one_hp).ok_or(Overflow)? >= new_in_hp.checked_mul(target_price_hp).ok_or(Overflow)?)
+    };
+
+    let (mut low, mut high) = (0, max_amount_in_for_target_price(in_reserve, out_reserve, target_price_1e18, fee)?);
+    while low < high {
+        let mid = high - (high - low) / 2;
+        match above_target(mid)? {
+            true => low = mid,
+            false => high = mid - 1,
+        }
+    }
+
+    Ok(low)
+}
+
+/// Calculating the swap that brings this pool to an external price, for rebalancing between pools.
+///
+/// - `reserve_in` - reserve amount of asset in
+/// - `reserve_out` - reserve amount of asset out
+/// - `external_price_1e18` - external price of asset in in asset out, scaled by 1e18
+/// - `fee` - fee charged on the amount sold
+///
+/// Returns direction, amount sold to the pool and amount received from the pool, or MathError in case of error
+pub fn sync_to_external_price(
+    reserve_in: Balance,
+    reserve_out: Balance,
+    external_price_1e18: Balance,
+    fee: Fee,
+) -> Result<(SwapDirection, Balance, Balance), MathError> {
+    let (direction, amount_in) = arbitrage_amount(reserve_in, reserve_out, external_price_1e18, fee)?;
+
+    let amount_out = match (amount_in, &direction) {
+        (0, _) => 0,
+        (_, SwapDirection::Sell) => calculate_out_given_in_with_fee(reserve_in, reserve_out, amount_in, fee)?,
+        (_, SwapDirection::Buy) => calculate_out_given_in_with_fee(reserve_out, reserve_in, amount_in, fee)?,
+    };
+
+    Ok((direction, amount_in, amount_out))
+}
+
+/// Calculating shares of an auto-compounding position that reinvests its fees at the end of every period.
+/// Each period earns FEE_APR * PERIOD_SECONDS / SECONDS_PER_YEAR, with a 365 day year,
+/// and the earnings are added to the shares before the next period starts.
+/// Formula : INITIAL_SHARES * (1 + FEE_APR * PERIOD_SECONDS / SECONDS_PER_YEAR) ^ PERIODS
+///
+/// - `initial_shares` - shares at the start
+/// - `fee_apr_1e18` - yearly fee yield, scaled by 1e18
+/// - `periods` - number of compounding periods
+/// - `period_seconds` - length of a period in seconds
+///
+/// Returns MathError in case of error
+pub fn compounded_shares(
+    initial_shares: Balance,
+    fee_apr_1e18: Balance,
+    periods: u32,
+    period_seconds: u64,
+) -> Result<Balance, MathError> {
+    let (shares_hp, apr_hp, period_hp, year_hp, fixed_one_hp) =
+        to_u256!(initial_shares, fee_apr_1e18, period_seconds, SECONDS_PER_YEAR, FIXED_ONE);
+
+    let period_rate_hp = apr_hp
+        .checked_mul(period_hp).ok_or(Overflow)?
+        .checked_div(year_hp).ok_or(Overflow)?;
+
+    let growth_hp = pow_fixed(fixed_one_hp.checked_add(period_rate_hp).ok_or(Overflow)?, periods)?;
+
+    let compounded_hp = shares_hp
+        .checked_mul(growth_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp).ok_or(Overflow)?;
+
+    to_balance!(compounded_hp)
+}
+
+/// Calculating the smallest amount in whose output exceeds the gas cost of the swap.
+/// The amount is found by bisection below the exact solution of OUT(AMOUNT_IN) = GAS + 1, raised to
+/// at least FEE_DEN / (FEE_DEN - FEE_NUM) since smaller amounts have no output left after the fee.
+/// Formula : (GAS + 1) * IN_RESERVE * FEE_DEN / ((FEE_DEN - FEE_NUM) * (OUT_RESERVE - GAS - 1))
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `fee` - fee charged on the amount in
+/// - `gas_in_out_asset` - gas cost of the swap, expressed in buying asset
+///
+/// Returns Overflow if no amount is economical within the pool, or MathError in case of other error
+pub fn min_economical_swap(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    fee: Fee,
+    gas_in_out_asset: Balance,
+) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(in_reserve != 0, ZeroInReserve);
+
+    let required_out = round_up!(gas_in_out_asset)?;
+    ensure!(required_out < out_reserve, Overflow);
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    let (in_reserve_hp, out_reserve_hp, required_out_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(in_reserve, out_reserve, required_out, fee_numerator, fee_denominator);
+
+    let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+
+    let numerator = required_out_hp
+        .checked_mul(in_reserve_hp).ok_or(Overflow)?
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?;
+    let denominator = fee_multiplier_hp
+        .checked_mul(out_reserve_hp - required_out_hp).ok_or(Overflow)?;
+    let (quotient, remainder) = numerator.div_mod(denominator);
+    let exact_upper = match remainder.is_zero() {
+        true => to_balance!(quotient)?,
+        false => round_up!(to_balance!(quotient)?)?,
+    };
+
+    let (min_effective, min_effective_remainder) = fee_denominator_hp.div_mod(fee_multiplier_hp);
+    let min_effective = match min_effective_remainder.is_zero() {
+        true => to_balance!(min_effective)?,
+        false => round_up!(to_balance!(min_effective)?)?,
+    };
+    let upper = exact_upper.max(min_effective);
+
+    let economical = |amount_in: Balance| -> Result<bool, MathError> {
+        Ok(amount_in != 0 && calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)? > gas_in_out_asset)
+    };
+    ensure!(economical(upper)?, Overflow);
+
+    let (mut low, mut high) = (0, upper);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match economical(mid)? {
+            true => high = mid,
+            false => low = mid + 1,
+        }
+    }
+
+    Ok(low)
+}
+
+/// Calculating reserves reconciled with the actual balances of the pool, which are taken as the truth.
+/// Rounding leaves dust in the pool, so actual balances may exceed tracked reserves by ACTUAL - TRACKED,
+/// but never fall below them.
+///
+/// - `tracked_a` - tracked reserve amount of asset a
+/// - `tracked_b` - tracked reserve amount of asset b
+/// - `actual_a` - actual balance of asset a
+/// - `actual_b` - actual balance of asset b
+///
+/// Returns MathError in case of error
+pub fn reconcile_reserves(
+    tracked_a: Balance,
+    tracked_b: Balance,
+    actual_a: Balance,
+    actual_b: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    ensure!(actual_a >= tracked_a && actual_b >= tracked_b, InsufficientLiquidity);
+
+    Ok((actual_a, actual_b))
+}
+
+/// Calculating the band of external prices within which arbitrage against the pool does not cover the fee.
+/// Formula A: OUT_RESERVE * 1e18 * (FEE_DEN - FEE_NUM) / (IN_RESERVE * FEE_DEN)
+/// Formula B: OUT_RESERVE * 1e18 * FEE_DEN / (IN_RESERVE * (FEE_DEN - FEE_NUM))
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `fee` - fee charged on the amount in
+///
+/// Returns (lower price, upper price) of selling asset in buying asset scaled by 1e18, or MathError in case of error
+pub fn no_arb_band(in_reserve: Balance, out_reserve: Balance, fee: Fee) -> Result<(Balance, Balance), MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(in_reserve != 0, ZeroInReserve);
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    let (in_reserve_hp, out_reserve_hp, fixed_one_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(in_reserve, out_reserve, FIXED_ONE, fee_numerator, fee_denominator);
+
+    let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+
+    let scaled_out_hp = out_reserve_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?;
+
+    let lower_hp = scaled_out_hp
+        .checked_mul(fee_multiplier_hp).ok_or(Overflow)?
+        .checked_div(in_reserve_hp.checked_mul(fee_denominator_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    let upper_hp = scaled_out_hp
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?
+        .checked_div(in_reserve_hp.checked_mul(fee_multiplier_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    Ok((to_balance!(lower_hp)?, to_balance!(upper_hp)?))
+}
+
+/// Calculating amount to be received from the pool given the amount to be sent to the pool, both reserves and fee,
+/// failing if the fee kept by the pool would grow the invariant beyond `max_k`.
+/// Formula : (IN_RESERVE + AMOUNT_IN) * (OUT_RESERVE - AMOUNT_OUT) <= MAX_K
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+/// - `max_k` - largest invariant allowed after the swap
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_capped_k(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+    max_k: U256,
+) -> Result<Balance, MathError> {
+    let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+
+    let new_in_reserve = in_reserve.checked_add(amount_in).ok_or(Overflow)?;
+    let new_out_reserve = out_reserve.checked_sub(amount_out).ok_or(InsufficientOutReserve)?;
+    let (new_in_reserve_hp, new_out_reserve_hp) = to_u256!(new_in_reserve, new_out_reserve);
+
+    let new_k = new_in_reserve_hp.checked_mul(new_out_reserve_hp).ok_or(Overflow)?;
+    ensure!(new_k <= max_k, KExceeded);
+
+    Ok(amount_out)
+}
+
+/// Calculating value of a single LP share in asset b, with the reserves valued at the given price.
+/// Formula : (ASSET_A_RESERVE * PRICE / 1e18 + ASSET_B_RESERVE) * 1e18 / TOTAL_SHARES
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `total_shares` - total shares of the pool
+/// - `price_1e18` - price of asset a in asset b, scaled by 1e18
+///
+/// Returns share value scaled by 1e18, or MathError in case of error
+pub fn lp_share_price(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    total_shares: Balance,
+    price_1e18: Balance,
+) -> Result<Balance, MathError> {
+    ensure!(total_shares != 0, ZeroInReserve);
+
+    let (a_reserve_hp, b_reserve_hp, shares_hp, price_hp, fixed_one_hp) =
+        to_u256!(reserve_a, reserve_b, total_shares, price_1e18, FIXED_ONE);
+
+    let pool_value_hp = a_reserve_hp
+        .checked_mul(price_hp).ok_or(Overflow)?
+        .checked_add(b_reserve_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    let share_price_hp = pool_value_hp.checked_div(shares_hp).ok_or(Overflow)?;
+
+    to_balance!(share_price_hp)
+}
+
+/// Calculating the change in value of a single LP share for a price change, the discrete derivative
+/// of `lp_share_price` between `price_1e18` and `price_1e18 + delta_price_1e18`.
+/// The reserves are held fixed, so the change is ASSET_A_RESERVE * DELTA_PRICE / TOTAL_SHARES and is not near zero
+/// even for a balanced pool. Rebalancing along the curve only departs from it by a second order term.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `total_shares` - total shares of the pool
+/// - `price_1e18` - price of asset a in asset b, scaled by 1e18
+/// - `delta_price_1e18` - price change, scaled by 1e18
+///
+/// Returns share value change scaled by 1e18, or MathError in case of error
+pub fn share_value_delta(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    total_shares: Balance,
+    price_1e18: Balance,
+    delta_price_1e18: Balance,
+) -> Result<Balance, MathError> {
+    let moved_price = price_1e18.checked_add(delta_price_1e18).ok_or(Overflow)?;
+
+    let value_before = lp_share_price(reserve_a, reserve_b, total_shares, price_1e18)?;
+    let value_after = lp_share_price(reserve_a, reserve_b, total_shares, moved_price)?;
+
+    value_after.checked_sub(value_before).ok_or(Overflow)
+}
+
+/// Calculating how much of the shortfall of a swap against the spot price is due to the fee and how much to slippage.
+/// Both costs are in buying asset and together make up SPOT_PRICE(AMOUNT_IN) - AMOUNT_OUT_WITH_FEE.
+/// Formula A: AMOUNT_OUT - AMOUNT_OUT_WITH_FEE
+/// Formula B: SPOT_PRICE(AMOUNT_IN) - AMOUNT_OUT
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns (fee cost, slippage cost), or MathError in case of error
+pub fn cost_breakdown(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    let ideal_out = calculate_spot_price(in_reserve, out_reserve, amount_in)?;
+    let amount_out = calculate_out_given_in(in_reserve, out_reserve, amount_in)?;
+    let amount_out_with_fee = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+
+    // Rounding can leave the output without fee above the ideal output, the total cost is never exceeded.
+    let total_cost = ideal_out.saturating_sub(amount_out_with_fee);
+    let fee_cost = amount_out.saturating_sub(amount_out_with_fee).min(total_cost);
+
+    Ok((fee_cost, total_cost - fee_cost))
+}
+
+/// Calculating reserves remaining in the pool after burning shares, complementing `calculate_liquidity_out`.
+/// Formula A: ASSET_A_RESERVE - SHARES * ASSET_A_RESERVE / TOTAL_SHARES
+/// Formula B: ASSET_B_RESERVE - SHARES * ASSET_B_RESERVE / TOTAL_SHARES
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `shares` - shares to be burned
+/// - `total_shares` - total shares of the pool
+///
+/// Returns MathError in case of error
+pub fn reserves_after_burn(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    shares: Balance,
+    total_shares: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    let (withdrawn_a, withdrawn_b) = calculate_liquidity_out(reserve_a, reserve_b, shares, total_shares)?;
+
+    let remaining_a = reserve_a.checked_sub(withdrawn_a).ok_or(InsufficientLiquidity)?;
+    let remaining_b = reserve_b.checked_sub(withdrawn_b).ok_or(InsufficientLiquidity)?;
+
+    Ok((remaining_a, remaining_b))
+}
+
+/// Calculating the worst average execution price among swaps applied one after another to the pool.
+/// Each swap moves the reserves for the next one, so later fills get worse prices.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amounts` - amounts sold by the swaps, in order
+/// - `fee` - fee charged on every swap
+///
+/// Returns worst price of selling asset in buying asset scaled by 1e18, or MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn worst_price_in_batch(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amounts: &[Balance],
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let (mut in_reserve, mut out_reserve) = (in_reserve, out_reserve);
+    let mut worst_price: Option<Balance> = None;
+
+    for &amount_in in amounts.iter().filter(|&&amount_in| amount_in != 0) {
+        let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+        let price = calculate_spot_price(amount_in, amount_out, FIXED_ONE)?;
+
+        worst_price = Some(worst_price.map_or(price, |worst| worst.min(price)));
+
+        in_reserve = in_reserve.checked_add(amount_in).ok_or(Overflow)?;
+        out_reserve = out_reserve.checked_sub(amount_out).ok_or(InsufficientOutReserve)?;
+    }
+
+    worst_price.ok_or(ZeroInReserve)
+}
+
+/// Calculating impermanent loss of a constant product position for a price move, scaled by 1e18.
+/// Formula : 1 - 2 * SQRT(PRICE_RATIO) / (1 + PRICE_RATIO)
+///
+/// - `price_ratio_1e18` - ratio of the new to the old price, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn impermanent_loss(price_ratio_1e18: Balance) -> Result<Balance, MathError> {
+    let (ratio_hp, fixed_one_hp) = to_u256!(price_ratio_1e18, FIXED_ONE);
+
+    let sqrt_ratio_hp = integer_sqrt(ratio_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?);
+
+    let value_hp = sqrt_ratio_hp
+        .checked_mul(fixed_one_hp * 2).ok_or(Overflow)?
+        .checked_div(fixed_one_hp.checked_add(ratio_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    to_balance!(fixed_one_hp.saturating_sub(value_hp))
+}
+
+/// Calculating the yearly volatility consistent with the impermanent loss observed over a period, scaled by 1e18.
+/// Assumes the observed loss is net of fees, so the gross loss is OBSERVED_IL + FEE_APR * PERIOD / YEAR,
+/// and that the price moved up by one standard deviation, PRICE_RATIO = 1 + VOLATILITY * SQRT(PERIOD / YEAR),
+/// with a 365 day year. The gross loss is inverted to the price ratio through `impermanent_loss`.
+/// Formula : (PRICE_RATIO - 1) / SQRT(PERIOD / YEAR)
+/// where SQRT(PRICE_RATIO) = (1 + SQRT(1 - (1 - IL)^2)) / (1 - IL)
+///
+/// - `fee_apr_1e18` - yearly fee yield of the position, scaled by 1e18
+/// - `observed_il_1e18` - impermanent loss net of fees observed over the period, scaled by 1e18
+/// - `period_seconds` - length of the period in seconds
+///
+/// Returns MathError in case of error
+pub fn implied_volatility(fee_apr_1e18: Balance, observed_il_1e18: Balance, period_seconds: u64) -> Result<Balance, MathError> {
+    ensure!(period_seconds != 0, ZeroInReserve);
+
+    let (fee_apr_hp, observed_il_hp, period_hp, year_hp, fixed_one_hp) =
+        to_u256!(fee_apr_1e18, observed_il_1e18, period_seconds, SECONDS_PER_YEAR, FIXED_ONE);
+
+    let period_years_hp = period_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(year_hp).ok_or(Overflow)?;
+
+    let fees_hp = fee_apr_hp
+        .checked_mul(period_years_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp).ok_or(Overflow)?;
+
+    let gross_il_hp = observed_il_hp.checked_add(fees_hp).ok_or(Overflow)?;
+    let ratio_hp = price_ratio_for_il(gross_il_hp)?;
+
+    let sqrt_period_hp = integer_sqrt(period_years_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?);
+    ensure!(!sqrt_period_hp.is_zero(), ZeroInReserve);
+
+    let volatility_hp = ratio_hp
+        .saturating_sub(fixed_one_hp)
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(sqrt_period_hp).ok_or(Overflow)?;
+
+    to_balance!(volatility_hp)
+}
+
+/// Inverts `impermanent_loss` to the price ratio above one causing the given loss, rounded down.
+/// Formula : SQRT(PRICE_RATIO) = (1 + SQRT(1 - (1 - IL)^2)) / (1 - IL)
+fn price_ratio_for_il(il_hp: U256) -> Result<U256, MathError> {
+    let fixed_one_hp = U256::from(FIXED_ONE);
+    ensure!(il_hp < fixed_one_hp, Overflow);
+
+    let value_hp = fixed_one_hp - il_hp;
+    let discriminant_hp = integer_sqrt(fixed_one_hp * fixed_one_hp - value_hp * value_hp);
+    let sqrt_ratio_hp = (fixed_one_hp + discriminant_hp)
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(value_hp).ok_or(Overflow)?;
+
+    Ok(sqrt_ratio_hp.checked_mul(sqrt_ratio_hp).ok_or(Overflow)? / fixed_one_hp)
+}
+
+/// Calculating the price deviation, scaled by 1e18, at which re-centering a managed position pays for its gas.
+/// Models an off-centre position as losing, in proportion to its deviation, both its fee capture
+/// and the protection against divergence loss, which accrues at VOLATILITY^2 / 8 a year.
+/// Rebalancing is worth it once a year of that loss covers the gas cost.
+/// Formula : GAS_COST_FRACTION / (FEE_APR + VOLATILITY^2 / 8)
+///
+/// - `fee_apr_1e18` - yearly fee yield of the position, scaled by 1e18
+/// - `gas_cost_fraction_1e18` - gas cost of a rebalance as a fraction of the position, scaled by 1e18
+/// - `volatility_1e18` - yearly volatility of the price, scaled by 1e18
+///
+/// Returns MathError in case of error
+pub fn rebalance_threshold(
+    fee_apr_1e18: Balance,
+    gas_cost_fraction_1e18: Balance,
+    volatility_1e18: Balance,
+) -> Result<Balance, MathError> {
+    let (fee_apr_hp, gas_cost_hp, volatility_hp, fixed_one_hp) =
+        to_u256!(fee_apr_1e18, gas_cost_fraction_1e18, volatility_1e18, FIXED_ONE);
+
+    let divergence_hp = volatility_hp
+        .checked_mul(volatility_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp * 8).ok_or(Overflow)?;
+
+    let loss_rate_hp = fee_apr_hp.checked_add(divergence_hp).ok_or(Overflow)?;
+    ensure!(!loss_rate_hp.is_zero(), ZeroInReserve);
+
+    let threshold_hp = gas_cost_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(loss_rate_hp).ok_or(Overflow)?;
+
+    to_balance!(threshold_hp)
+}
+
+/// Calculating amount to be received from the pool, checked against both a minimum output and a maximum price impact.
+/// Price impact is checked first: a swap tripping both bounds returns ExcessivePriceImpact,
+/// since the impact is what drives the output below the minimum.
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `min_out` - minimum amount to be received
+/// - `max_impact_1e18` - maximum price impact, as measured by `calculate_price_impact`, scaled by 1e18
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn validate_swap(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    min_out: Balance,
+    max_impact_1e18: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let (amount_out, price_impact) = net_quote(in_reserve, out_reserve, amount_in, fee)?;
+
+    ensure!(price_impact <= max_impact_1e18, ExcessivePriceImpact);
+    ensure!(amount_out >= min_out, SlippageExceeded);
+
+    Ok(amount_out)
+}
+
+/// Calculating the amounts lost to rounding when a deposit is withdrawn straight away.
+/// Shares are minted with `calculate_shares_minted_safe` and burnt with `calculate_liquidity_out`
+/// against the reserves and shares after the deposit. Any part of the deposit beyond the pool ratio
+/// is not backed by shares and is counted as lost.
+/// Formula A: AMOUNT_A - WITHDRAWN_A
+/// Formula B: AMOUNT_B - WITHDRAWN_B
+///
+/// - `amount_a` - deposited amount of asset a
+/// - `amount_b` - deposited amount of asset b
+/// - `reserve_a` - reserve amount of asset a before the deposit
+/// - `reserve_b` - reserve amount of asset b before the deposit
+/// - `total_shares` - total shares before the deposit
+///
+/// Returns (asset a lost, asset b lost), or MathError in case of error
+pub fn round_trip_share_loss(
+    amount_a: Balance,
+    amount_b: Balance,
+    reserve_a: Balance,
+    reserve_b: Balance,
+    total_shares: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    let shares = calculate_shares_minted_safe(reserve_a, reserve_b, amount_a, amount_b, total_shares)?;
+
+    let new_reserve_a = reserve_a.checked_add(amount_a).ok_or(Overflow)?;
+    let new_reserve_b = reserve_b.checked_add(amount_b).ok_or(Overflow)?;
+    let new_total_shares = total_shares.checked_add(shares).ok_or(Overflow)?;
+
+    let (withdrawn_a, withdrawn_b) = calculate_liquidity_out(new_reserve_a, new_reserve_b, shares, new_total_shares)?;
+
+    let lost_a = amount_a.checked_sub(withdrawn_a).ok_or(Overflow)?;
+    let lost_b = amount_b.checked_sub(withdrawn_b).ok_or(Overflow)?;
+
+    Ok((lost_a, lost_b))
+}
+
+/// Calculating a band of one standard deviation around the mean of recent price samples.
+/// A current price outside of the band hints at a manipulated pool.
+/// Formula : MEAN -/+ SQRT(SUM((PRICE - MEAN)^2) / N)
+///
+/// - `price_samples` - recent prices, scaled by 1e18
+///
+/// Returns (lower bound, upper bound), or MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn price_confidence_band(price_samples: &[Balance]) -> Result<(Balance, Balance), MathError> {
+    ensure!(!price_samples.is_empty(), ZeroInReserve);
+
+    let count_hp = U256::from(price_samples.len());
+
+    let sum_hp = price_samples
+        .iter()
+        .try_fold(U256::zero(), |acc, &price| acc.checked_add(U256::from(price)).ok_or(Overflow))?;
+    let mean_hp = sum_hp / count_hp;
+
+    let squared_deviation_hp = price_samples.iter().try_fold(U256::zero(), |acc, &price| {
+        let price_hp = U256::from(price);
+        let deviation_hp = if price_hp > mean_hp { price_hp - mean_hp } else { mean_hp - price_hp };
+        acc.checked_add(deviation_hp.checked_mul(deviation_hp).ok_or(Overflow)?).ok_or(Overflow)
+    })?;
+    let stddev_hp = integer_sqrt(squared_deviation_hp / count_hp);
+
+    let lower = to_balance!(mean_hp.saturating_sub(stddev_hp))?;
+    let upper = to_balance!(mean_hp.checked_add(stddev_hp).ok_or(Overflow)?)?;
+
+    Ok((lower, upper))
+}
+
+/// Calculating the symmetric price band [PRICE / R, PRICE * R] within which the impermanent loss of a full range
+/// position stays under `max_il`, by inverting `impermanent_loss`. The band is rounded inwards,
+/// so the loss at its edges never exceeds the tolerance.
+/// Formula : SQRT(R) = (1 + SQRT(1 - (1 - MAX_IL)^2)) / (1 - MAX_IL)
+///
+/// - `price_1e18` - price of asset a in asset b, scaled by 1e18
+/// - `max_il_1e18` - impermanent loss tolerated at the band edges, scaled by 1e18
+///
+/// Returns (lowest price, highest price) scaled by 1e18, or MathError in case of error
+pub fn il_tolerance_band(price_1e18: Balance, max_il_1e18: Balance) -> Result<(Balance, Balance), MathError> {
+    ensure!(price_1e18 != 0, ZeroInReserve);
+
+    let (price_hp, max_il_hp, fixed_one_hp) = to_u256!(price_1e18, max_il_1e18, FIXED_ONE);
+
+    let ratio_hp = price_ratio_for_il(max_il_hp)?;
+
+    let (lowest_price_hp, remainder) = price_hp.checked_mul(fixed_one_hp).ok_or(Overflow)?.div_mod(ratio_hp);
+    let lowest_price_hp = match remainder.is_zero() {
+        true => lowest_price_hp,
+        false => lowest_price_hp + 1,
+    };
+    let highest_price_hp = price_hp.checked_mul(ratio_hp).ok_or(Overflow)? / fixed_one_hp;
+
+    Ok((to_balance!(lowest_price_hp)?, to_balance!(highest_price_hp)?))
+}
+
+/// Calculating reserves of a full range position of `capital` whose impermanent loss stays under `max_il`
+/// within the symmetric band of `il_tolerance_band`.
+/// The loss of a full range position depends on the price move only, so the tolerance sets the band and not the reserves,
+/// and the loss is the same at both edges when the capital is split evenly by value.
+/// Formula A: CAPITAL / 2 * 1e18 / PRICE
+/// Formula B: CAPITAL / 2
+///
+/// - `price_1e18` - price of asset a in asset b, scaled by 1e18
+/// - `max_il_1e18` - impermanent loss tolerated at the band edges, scaled by 1e18
+/// - `capital` - value of the position in asset b
+///
+/// Returns (asset a reserve, asset b reserve), or MathError in case of error
+pub fn reserves_for_il_tolerance(
+    price_1e18: Balance,
+    max_il_1e18: Balance,
+    capital: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    il_tolerance_band(price_1e18, max_il_1e18)?;
+
+    let (price_hp, capital_hp, fixed_one_hp) = to_u256!(price_1e18, capital, FIXED_ONE);
+
+    let reserve_b_hp = capital_hp / 2;
+    let reserve_a_hp = reserve_b_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(price_hp).ok_or(Overflow)?;
+
+    Ok((to_balance!(reserve_a_hp)?, to_balance!(reserve_b_hp)?))
+}
+
+/// Calculating amount to be received from the pool, refusing to quote against reserves older than `max_age_blocks`.
+/// Formula : OUT_RESERVE * AMOUNT_IN_AFTER_FEE / (IN_RESERVE + AMOUNT_IN_AFTER_FEE)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `reserve_age_blocks` - blocks since the reserves were last updated
+/// - `max_age_blocks` - oldest reserves accepted, in blocks
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_fresh(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    reserve_age_blocks: u64,
+    max_age_blocks: u64,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    ensure!(reserve_age_blocks <= max_age_blocks, StaleReserves);
+
+    calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)
+}
+
+/// Calculating fee revenue earned over a window per unit of liquidity, scaled by 1e18.
+/// Formula : VOLUME_WINDOW * FEE_NUM * 1e18 / (FEE_DEN * RESERVE)
+///
+/// - `volume_window` - amount traded during the window
+/// - `reserve` - reserve amount of the traded asset
+/// - `fee` - fee charged on the traded amount
+///
+/// Returns MathError in case of error
+pub fn marginal_fee_yield(volume_window: Balance, reserve: Balance, fee: Fee) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(reserve != 0, ZeroInReserve);
+
+    let (fee_numerator, fee_denominator) = fee;
+    let (volume_hp, reserve_hp, fee_numerator_hp, fee_denominator_hp, fixed_one_hp) =
+        to_u256!(volume_window, reserve, fee_numerator, fee_denominator, FIXED_ONE);
+
+    let fee_yield_hp = volume_hp
+        .checked_mul(fee_numerator_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(fee_denominator_hp.checked_mul(reserve_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    to_balance!(fee_yield_hp)
+}
+
+/// Calculating how to split a trade between two pools of the same pair so that both stay close to an anchor price.
+/// Formula : MIN((SQRT(ANCHOR / PRICE_A) - 1)^2 + (SQRT(ANCHOR / PRICE_B) - 1)^2)
+/// where ANCHOR / PRICE = (IN_RESERVE + AMOUNT_IN) * (D * IN_RESERVE + G * AMOUNT_IN) * ANCHOR / (D * IN_RESERVE * OUT_RESERVE)
+/// of each pool after its part of the trade, D being the fee denominator and G the denominator less the numerator.
+///
+/// - `pool_a` - (in reserve, out reserve) of the first pool
+/// - `pool_b` - (in reserve, out reserve) of the second pool
+/// - `amount_in` - amount to be sold
+/// - `anchor_price_1e18` - price of the selling asset in the buying asset reported by the feed, scaled by 1e18
+/// - `fee` - fee charged by both pools
+///
+/// Returns (amount to the first pool, amount to the second pool), or MathError in case of error
+pub fn anchored_split(
+    pool_a: (Balance, Balance),
+    pool_b: (Balance, Balance),
+    amount_in: Balance,
+    anchor_price_1e18: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(pool_a.0 != 0 && pool_a.1 != 0 && pool_b.0 != 0 && pool_b.1 != 0, ZeroInReserve);
+    ensure!(anchor_price_1e18 != 0, ZeroInReserve);
+
+    let (fee_numerator, fee_denominator) = fee;
+    let (anchor_hp, fee_multiplier_hp, fee_denominator_hp, fixed_one_hp) =
+        to_u256!(anchor_price_1e18, fee_denominator - fee_numerator, fee_denominator, FIXED_ONE);
+
+    let deviation = |(in_reserve, out_reserve): (Balance, Balance), amount: Balance| -> Result<U256, MathError> {
+        let (in_reserve_hp, out_reserve_hp, amount_hp) = to_u256!(in_reserve, out_reserve, amount);
+
+        let in_growth_hp = in_reserve_hp
+            .checked_add(amount_hp).ok_or(Overflow)?
+            .checked_mul(fixed_one_hp).ok_or(Overflow)?
+            / in_reserve_hp;
+        let out_shrink_hp = fee_denominator_hp
+            .checked_mul(in_reserve_hp).ok_or(Overflow)?
+            .checked_add(fee_multiplier_hp.checked_mul(amount_hp).ok_or(Overflow)?).ok_or(Overflow)?
+            .checked_mul(anchor_hp).ok_or(Overflow)?
+            / fee_denominator_hp.checked_mul(out_reserve_hp).ok_or(Overflow)?;
+
+        let relative_hp = integer_sqrt(in_growth_hp.checked_mul(out_shrink_hp).ok_or(Overflow)?);
+        let deviation_hp = if relative_hp > fixed_one_hp { relative_hp - fixed_one_hp } else { fixed_one_hp - relative_hp };
+        deviation_hp.checked_mul(deviation_hp).ok_or(Overflow)
+    };
+    let cost = |to_a: Balance| -> Result<U256, MathError> {
+        deviation(pool_a, to_a)?.checked_add(deviation(pool_b, amount_in - to_a)?).ok_or(Overflow)
+    };
+
+    // SQRT(ANCHOR / PRICE) grows almost linearly with the amount along the curve, so the cost is convex in the split
+    // and the first split from which sending one more unit to the first pool stops helping is the minimum.
+    // Squared price deviations flatten out below the anchor, as the price cannot fall below zero, and are not convex.
+    let (mut low, mut high) = (0, amount_in);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if cost(middle + 1)? >= cost(middle)? {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    Ok((low, amount_in - low))
+}
+
+/// Calculating prices of asset a in asset b, scaled by 1e18, at which a leveraged LP position is worth less than its debt.
+/// Along the curve the position is worth 2 * SQRT(POSITION_A * POSITION_B * PRICE) in asset b,
+/// its holding value less the impermanent loss. That value rises with the price, so a debt in asset b can only
+/// put the position underwater on the downside and the upper bound is Balance::MAX, as no price above ever liquidates.
+/// Formula : BORROWED_B^2 / (4 * POSITION_A * POSITION_B)
+/// where POSITION is RESERVE * SHARES / TOTAL_SHARES of each asset.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `borrowed_b` - debt of the position in asset b
+/// - `shares` - shares of the position
+/// - `total_shares` - total shares of the pool
+///
+/// Returns (lower price bound, upper price bound), or MathError in case of error
+pub fn leveraged_liquidation_price(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    borrowed_b: Balance,
+    shares: Balance,
+    total_shares: Balance,
+) -> Result<(Balance, Balance), MathError> {
+    let (position_a, position_b) = calculate_liquidity_out(reserve_a, reserve_b, shares, total_shares)?;
+
+    if borrowed_b == 0 {
+        return Ok((0, Balance::MAX));
+    }
+    ensure!(position_a != 0 && position_b != 0, ZeroInReserve);
+
+    let (position_a_hp, position_b_hp, borrowed_hp, fixed_one_hp) =
+        to_u256!(position_a, position_b, borrowed_b, FIXED_ONE);
+
+    let liquidation_price_hp = borrowed_hp
+        .checked_mul(borrowed_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(
+            position_a_hp
+                .checked_mul(position_b_hp).ok_or(Overflow)?
+                .checked_mul(U256::from(4)).ok_or(Overflow)?,
+        ).ok_or(Overflow)?;
+
+    Ok((to_balance!(liquidation_price_hp)?, Balance::MAX))
+}
+
+/// Calculating shares minted to the protocol for the fees accrued since k was last recorded.
+/// The protocol takes a sixth of the growth of SQRT(K), as in Uniswap v2. A zero `k_last` means the protocol fee
+/// was off, so nothing has accrued.
+/// Formula : TOTAL_SHARES * (SQRT(K) - SQRT(K_LAST)) / (5 * SQRT(K) + SQRT(K_LAST))
+///
+/// - `total_shares` - total shares of the pool
+/// - `k_last` - product of the reserves when the protocol fee was last minted
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+///
+/// Returns MathError in case of error
+pub fn calculate_fees_accrued(
+    total_shares: Balance,
+    k_last: U256,
+    reserve_a: Balance,
+    reserve_b: Balance,
+) -> Result<Balance, MathError> {
+    if k_last.is_zero() {
+        return Ok(0);
+    }
+
+    let (shares_hp, a_reserve_hp, b_reserve_hp) = to_u256!(total_shares, reserve_a, reserve_b);
+
+    let root_k_hp = integer_sqrt(a_reserve_hp.checked_mul(b_reserve_hp).ok_or(Overflow)?);
+    let root_k_last_hp = integer_sqrt(k_last);
+
+    if root_k_hp <= root_k_last_hp {
+        return Ok(0);
+    }
+
+    let fee_shares_hp = shares_hp
+        .checked_mul(root_k_hp - root_k_last_hp).ok_or(Overflow)?
+        .checked_div(
+            root_k_hp
+                .checked_mul(U256::from(5)).ok_or(Overflow)?
+                .checked_add(root_k_last_hp).ok_or(Overflow)?,
+        ).ok_or(Overflow)?;
+
+    to_balance!(fee_shares_hp)
+}
+
+/// Calculating total shares after minting the protocol fee, which dilutes LPs while the reserves stay the same.
+/// Formula : TOTAL_SHARES + FEES_ACCRUED
+///
+/// - `total_shares` - total shares of the pool
+/// - `k_last` - product of the reserves when the protocol fee was last minted
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+///
+/// Returns MathError in case of error
+pub fn apply_protocol_fee_mint(
+    total_shares: Balance,
+    k_last: U256,
+    reserve_a: Balance,
+    reserve_b: Balance,
+) -> Result<Balance, MathError> {
+    let fee_shares = calculate_fees_accrued(total_shares, k_last, reserve_a, reserve_b)?;
+
+    total_shares.checked_add(fee_shares).ok_or(Overflow)
+}
+
+/// Calculating amount to be received from the pool, refusing swaps that leave the price outside of a floor and a ceiling.
+/// Bounds apply to the marginal price of the selling asset in the buying asset after the swap. To bound a buy
+/// of the same asset, pass the reserves flipped and the bounds inverted, 1e36 / CEILING as floor and 1e36 / FLOOR as ceiling.
+/// Formula : OUT_RESERVE * AMOUNT_IN_AFTER_FEE / (IN_RESERVE + AMOUNT_IN_AFTER_FEE)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `price_floor_1e18` - lowest price accepted after the swap, scaled by 1e18
+/// - `price_ceiling_1e18` - highest price accepted after the swap, scaled by 1e18
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_with_bounds(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    price_floor_1e18: Balance,
+    price_ceiling_1e18: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let amount_out = calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)?;
+    ensure!(amount_out <= out_reserve, InsufficientOutReserve);
+
+    let (in_after_hp, out_after_hp, fixed_one_hp) =
+        to_u256!(in_reserve.checked_add(amount_in).ok_or(Overflow)?, out_reserve - amount_out, FIXED_ONE);
+
+    let price_hp = out_after_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(in_after_hp).ok_or(Overflow)?;
+
+    ensure!(price_hp >= U256::from(price_floor_1e18) && price_hp <= U256::from(price_ceiling_1e18), PriceBoundBreached);
+
+    Ok(amount_out)
+}
+
+/// Calculating the value in asset a an LP recovers after asset b depegged to a new true price.
+/// Assumes arbitrage moves the pool to the true price at constant k before the LP withdraws,
+/// which leaves the position worth less than its current reserves valued at that price.
+/// Formula : 2 * SQRT(POSITION_A * POSITION_B * TRUE_PRICE / 1e18)
+/// where POSITION is RESERVE * SHARES / TOTAL_SHARES of each asset.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `true_price_b_in_a_1e18` - price of asset b in asset a after the depeg, scaled by 1e18
+/// - `shares` - shares of the position
+/// - `total_shares` - total shares of the pool
+///
+/// Returns MathError in case of error
+pub fn recoverable_value(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    true_price_b_in_a_1e18: Balance,
+    shares: Balance,
+    total_shares: Balance,
+) -> Result<Balance, MathError> {
+    let (position_a, position_b) = calculate_liquidity_out(reserve_a, reserve_b, shares, total_shares)?;
+
+    let (position_a_hp, position_b_hp, price_hp, fixed_one_hp) =
+        to_u256!(position_a, position_b, true_price_b_in_a_1e18, FIXED_ONE);
+
+    let value_squared_hp = position_a_hp
+        .checked_mul(position_b_hp).ok_or(Overflow)?
+        .checked_mul(price_hp).ok_or(Overflow)?
+        .checked_div(fixed_one_hp).ok_or(Overflow)?;
+
+    to_balance!(integer_sqrt(value_squared_hp).checked_mul(U256::from(2)).ok_or(Overflow)?)
+}
+
+/// Calculating outputs of a coordinated two-sided trade, where both assets come in at once and the pool nets them.
+/// The smaller side is matched against the same value of the larger side at the spot price, both charged the fee,
+/// and only the excess of the larger side is swapped along the curve, against the reserves after matching.
+/// Formula A: MATCHED_A * (FEE_DEN - FEE_NUM) / FEE_DEN
+/// Formula B: AMOUNT_B_IN * (FEE_DEN - FEE_NUM) / FEE_DEN + OUT_GIVEN_IN(AMOUNT_A_IN - MATCHED_A)
+/// where MATCHED_A is AMOUNT_B_IN * RESERVE_A / RESERVE_B, shown here for asset a being the larger side.
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `amount_a_in` - amount of asset a sold
+/// - `amount_b_in` - amount of asset b sold
+/// - `fee` - fee charged on both amounts
+///
+/// Returns (amount of asset a out, amount of asset b out), or MathError in case of error
+pub fn two_sided_trade(
+    reserve_a: Balance,
+    reserve_b: Balance,
+    amount_a_in: Balance,
+    amount_b_in: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    ensure!(reserve_a != 0 && reserve_b != 0, ZeroInReserve);
+
+    let (reserve_a_hp, reserve_b_hp, amount_a_hp, amount_b_hp) = to_u256!(reserve_a, reserve_b, amount_a_in, amount_b_in);
+
+    let value_a_hp = amount_a_hp.checked_mul(reserve_b_hp).ok_or(Overflow)?;
+    let value_b_hp = amount_b_hp.checked_mul(reserve_a_hp).ok_or(Overflow)?;
+
+    if value_a_hp >= value_b_hp {
+        net_two_sided_trade(reserve_a, reserve_b, amount_a_in, amount_b_in, fee)
+    } else {
+        let (amount_b_out, amount_a_out) = net_two_sided_trade(reserve_b, reserve_a, amount_b_in, amount_a_in, fee)?;
+        Ok((amount_a_out, amount_b_out))
+    }
+}
+
+/// Nets a two-sided trade whose asset x side is worth at least its asset y side, returning (x out, y out).
+fn net_two_sided_trade(
+    reserve_x: Balance,
+    reserve_y: Balance,
+    amount_x_in: Balance,
+    amount_y_in: Balance,
+    fee: Fee,
+) -> Result<(Balance, Balance), MathError> {
+    let matched_x = calculate_spot_price(reserve_y, reserve_x, amount_y_in)?;
+    let excess_x = amount_x_in.checked_sub(matched_x).ok_or(Overflow)?;
+
+    let matched_x_out = apply_slippage_down(matched_x, fee)?;
+    let matched_y_out = apply_slippage_down(amount_y_in, fee)?;
+
+    // Fees on the matched amounts stay in the pool
+    let reserve_x_after = reserve_x.checked_add(matched_x - matched_x_out).ok_or(Overflow)?;
+    let reserve_y_after = reserve_y.checked_add(amount_y_in - matched_y_out).ok_or(Overflow)?;
+
+    let excess_y_out = match excess_x {
+        0 => 0,
+        _ => calculate_out_given_in_with_fee(reserve_x_after, reserve_y_after, excess_x, fee)?,
+    };
+
+    Ok((matched_x_out, matched_y_out.checked_add(excess_y_out).ok_or(Overflow)?))
+}
+
+/// Calculating the smallest back-run, selling asset out to the pool right after a prior sale of asset in,
+/// that profits after gas. The profit is the asset in received, valued at the spot price before the prior swap,
+/// less the amount sold and gas. It grows until the marginal output drops to that price, at
+/// `max_amount_in_for_target_price` of IN_RESERVE * 1e18 / OUT_RESERVE * FEE_DEN / (FEE_DEN - FEE_NUM),
+/// and the smallest profitable amount below it is found by bisection.
+///
+/// - `reserve_in` - reserve amount of the asset sold by the prior swap, before it
+/// - `reserve_out` - reserve amount of the asset bought by the prior swap, before it
+/// - `prior_swap_amount_in` - amount sold by the prior swap
+/// - `fee` - fee charged on both swaps
+/// - `gas_in_out_asset` - gas cost of the back-run in asset out
+///
+/// Returns the amount of asset out to sell, 0 if no back-run is profitable, or MathError in case of error
+pub fn min_backrun_size(
+    reserve_in: Balance,
+    reserve_out: Balance,
+    prior_swap_amount_in: Balance,
+    fee: Fee,
+    gas_in_out_asset: Balance,
+) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(reserve_in != 0 && reserve_out != 0, ZeroInReserve);
+
+    let prior_amount_out = calculate_out_given_in_with_fee(reserve_in, reserve_out, prior_swap_amount_in, fee)?;
+    let in_after = reserve_in.checked_add(prior_swap_amount_in).ok_or(Overflow)?;
+    let out_after = reserve_out.checked_sub(prior_amount_out).ok_or(InsufficientOutReserve)?;
+    ensure!(out_after != 0, InsufficientOutReserve);
+
+    let is_profitable = |amount: Balance| -> Result<bool, MathError> {
+        let amount_received = calculate_out_given_in_with_fee(out_after, in_after, amount, fee)?;
+        let value = calculate_spot_price(reserve_in, reserve_out, amount_received)?;
+        Ok(value > amount.checked_add(gas_in_out_asset).ok_or(Overflow)?)
+    };
+
+    let (fee_numerator, fee_denominator) = fee;
+    ensure!(fee_numerator < fee_denominator, InvalidFee);
+    let (reserve_in_hp, reserve_out_hp, fixed_one_hp, fee_numerator_hp, fee_denominator_hp) =
+        to_u256!(reserve_in, reserve_out, FIXED_ONE, fee_numerator, fee_denominator);
+
+    let fee_multiplier_hp = fee_denominator_hp - fee_numerator_hp;
+
+    let target_price_hp = reserve_in_hp
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_mul(fee_denominator_hp).ok_or(Overflow)?
+        .checked_div(reserve_out_hp.checked_mul(fee_multiplier_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    let most_profitable = max_amount_in_for_target_price(out_after, in_after, to_balance!(target_price_hp)?, fee)?;
+
+    if most_profitable == 0 || !is_profitable(most_profitable)? {
+        return Ok(0);
+    }
+
+    let (mut low, mut high) = (0, most_profitable);
+    while high - low > 1 {
+        let mid = high - (high - low) / 2;
+        if is_profitable(mid)? {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    Ok(high)
+}
+
+/// Calculating the yearly loss-versus-rebalancing of a constant product pool, in asset b.
+/// Without fees, LVR is VOLATILITY^2 / 8 of the pool value a year. With fees, arbitrageurs only trade once the
+/// mispricing exceeds the fee, which happens in a share 1 / (1 + Z) of the blocks, following Milionis et al.
+/// Assumes the price follows a geometric Brownian motion, the pool sits at the external price and
+/// arbitrageurs may trade every 12 second block, with a 365 day year.
+/// Formula : VOLATILITY^2 / 8 * 2 * RESERVE_B / (1 + Z)
+/// where Z = FEE_NUM / FEE_DEN * SQRT(2 * SECONDS_PER_YEAR / BLOCK_SECONDS) / VOLATILITY
+///
+/// - `reserve_a` - reserve amount of asset a
+/// - `reserve_b` - reserve amount of asset b
+/// - `volatility_1e18` - yearly volatility of the price of asset a in asset b, scaled by 1e18
+/// - `fee` - fee charged on the amount in
+///
+/// Returns MathError in case of error
+pub fn lvr_estimate(reserve_a: Balance, reserve_b: Balance, volatility_1e18: Balance, fee: Fee) -> Result<Balance, MathError> {
+    ensure_valid_fee(fee)?;
+    ensure!(reserve_a != 0 && reserve_b != 0, ZeroInReserve);
+
+    if volatility_1e18 == 0 {
+        return Ok(0);
+    }
+
+    let (fee_numerator, fee_denominator) = fee;
+    let (reserve_b_hp, volatility_hp, fee_numerator_hp, fee_denominator_hp, year_hp, block_hp, fixed_one_hp) =
+        to_u256!(reserve_b, volatility_1e18, fee_numerator, fee_denominator, SECONDS_PER_YEAR, BLOCK_SECONDS, FIXED_ONE);
+
+    let blocks_factor_hp = integer_sqrt(
+        year_hp
+            .checked_mul(U256::from(2)).ok_or(Overflow)?
+            .checked_mul(fixed_one_hp * fixed_one_hp).ok_or(Overflow)?
+            .checked_div(block_hp).ok_or(Overflow)?,
+    );
+
+    let trade_ratio_hp = fee_numerator_hp
+        .checked_mul(blocks_factor_hp).ok_or(Overflow)?
+        .checked_mul(fixed_one_hp).ok_or(Overflow)?
+        .checked_div(fee_denominator_hp.checked_mul(volatility_hp).ok_or(Overflow)?).ok_or(Overflow)?;
+
+    let pool_value_hp = reserve_b_hp.checked_mul(U256::from(2)).ok_or(Overflow)?;
+
+    let lvr_hp = volatility_hp
+        .checked_mul(volatility_hp).ok_or(Overflow)?
+        .checked_mul(pool_value_hp).ok_or(Overflow)?
+        .checked_div(
+            fixed_one_hp
+                .checked_mul(U256::from(8)).ok_or(Overflow)?
+                .checked_mul(fixed_one_hp.checked_add(trade_ratio_hp).ok_or(Overflow)?).ok_or(Overflow)?,
+        ).ok_or(Overflow)?;
+
+    to_balance!(lvr_hp)
+}
+
+/// Calculating amount to be received from the pool, refusing swaps that would take the block's volume over its cap.
+/// Formula : OUT_RESERVE * AMOUNT_IN_AFTER_FEE / (IN_RESERVE + AMOUNT_IN_AFTER_FEE)
+///
+/// - `in_reserve` - reserve amount of selling asset
+/// - `out_reserve` - reserve amount of buying asset
+/// - `amount_in` - amount
+/// - `block_volume_used` - amount already sold to the pool in the current block
+/// - `block_volume_cap` - largest amount the pool accepts in a block
+/// - `fee` - fee charged on `amount_in`
+///
+/// Returns MathError in case of error
+pub fn calculate_out_given_in_rate_limited(
+    in_reserve: Balance,
+    out_reserve: Balance,
+    amount_in: Balance,
+    block_volume_used: Balance,
+    block_volume_cap: Balance,
+    fee: Fee,
+) -> Result<Balance, MathError> {
+    let block_volume = block_volume_used.checked_add(amount_in).ok_or(VolumeCapExceeded)?;
+    ensure!(block_volume <= block_volume_cap, VolumeCapExceeded);
+
+    calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee)
+}
+
+/// Calculating the pool state after replaying a sequence of swaps, deposits and withdrawals.
+/// Swaps use `calculate_out_given_in_with_fee`, deposits mint `calculate_shares_minted_safe` while adding
+/// both amounts to the reserves, and withdrawals pay out `calculate_liquidity_out`.
+///
+/// - `initial` - pool state before the first operation
+/// - `ops` - operations to apply, in order
+/// - `fee` - fee charged on every swap
+///
+/// Returns MathError in case of error
+#[cfg(feature = "alloc")]
+pub fn simulate_sequence(initial: PoolState, ops: &[Op], fee: Fee) -> Result<PoolState, MathError> {
+    ops.iter().try_fold(initial, |state, op| {
+        let PoolState { reserve_a, reserve_b, total_shares } = state;
+
+        match *op {
+            Op::SwapAForB(amount_in) => {
+                let amount_out = calculate_out_given_in_with_fee(reserve_a, reserve_b, amount_in, fee)?;
+                Ok(PoolState {
+                    reserve_a: reserve_a.checked_add(amount_in).ok_or(Overflow)?,
+                    reserve_b: reserve_b.checked_sub(amount_out).ok_or(InsufficientOutReserve)?,
+                    total_shares,
+                })
+            }
+            Op::SwapBForA(amount_in) => {
+                let amount_out = calculate_out_given_in_with_fee(reserve_b, reserve_a, amount_in, fee)?;
+                Ok(PoolState {
+                    reserve_a: reserve_a.checked_sub(amount_out).ok_or(InsufficientOutReserve)?,
+                    reserve_b: reserve_b.checked_add(amount_in).ok_or(Overflow)?,
+                    total_shares,
+                })
+            }
+            Op::AddLiquidity(amount_a, amount_b) => {
+                let shares = calculate_shares_minted_safe(reserve_a, reserve_b, amount_a, amount_b, total_shares)?;
+                Ok(PoolState {
+                    reserve_a: reserve_a.checked_add(amount_a).ok_or(Overflow)?,
+                    reserve_b: reserve_b.checked_add(amount_b).ok_or(Overflow)?,
+                    total_shares: total_shares.checked_add(shares).ok_or(Overflow)?,
+                })
+            }
+            Op::RemoveLiquidity(shares) => {
+                let (remaining_a, remaining_b) = reserves_after_burn(reserve_a, reserve_b, shares, total_shares)?;
+                Ok(PoolState {
+                    reserve_a: remaining_a,
+                    reserve_b: remaining_b,
+                    total_shares: total_shares.checked_sub(shares).ok_or(InsufficientLiquidity)?,
+                })
+            }
+        }
+    })
+}
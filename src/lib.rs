@@ -5,7 +5,14 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub mod amm;
+pub mod math;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod test_helpers;
+#[cfg(all(test, feature = "std"))]
 mod tests;
 
 pub use amm::*;
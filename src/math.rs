@@ -0,0 +1,151 @@
+//! Integer helpers backing the pool calculations.
+
+use crate::MathError::{self, Overflow, ZeroInReserve};
+use primitive_types::U256;
+
+pub(crate) const FIXED_ONE: u128 = 1_000_000_000_000_000_000;
+
+/// Calculating the integer square root, rounded down.
+/// Uses Newton's method starting from a power of two above the root.
+///
+/// - `n` - number to take the square root of
+pub fn integer_sqrt(n: U256) -> U256 {
+    if n.is_zero() {
+        return n;
+    }
+
+    let mut x = U256::one() << ((n.bits() + 1) >> 1);
+
+    loop {
+        let y = (x + n / x) >> 1;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+/// Calculating a fixed point number scaled by 1e18 raised to an integer power, by repeated squaring.
+/// Every multiplication is rounded down.
+///
+/// - `base_1e18` - base, scaled by 1e18
+/// - `exponent` - power to raise the base to
+///
+/// Returns MathError in case of error
+pub fn pow_fixed(base_1e18: U256, exponent: u32) -> Result<U256, MathError> {
+    let fixed_one = U256::from(FIXED_ONE);
+    let mul = |a: U256, b: U256| a.checked_mul(b).map(|product| product / fixed_one).ok_or(Overflow);
+
+    let mut result = fixed_one;
+    let mut base = base_1e18;
+    let mut exponent = exponent;
+
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = mul(base, base)?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// Internal scale of the logarithm and exponential series, 1e36.
+const SERIES_ONE: u128 = 1_000_000_000_000_000_000_000_000_000_000_000_000;
+
+/// ln(2) scaled by 1e36.
+const LN_2: u128 = 693_147_180_559_945_309_417_232_121_458_176_568;
+
+/// Calculating a fixed point number scaled by 1e18 raised to a fractional power.
+/// Formula : BASE ^ (NUMERATOR / DENOMINATOR) = exp(ln(BASE) * NUMERATOR / DENOMINATOR)
+///
+/// The logarithm and exponential are evaluated as series scaled by 1e36, so the error is
+/// dominated by the final truncation to 1e18: the result is below the exact value by less
+/// than one part in 1e18 plus one unit. A zero base gives zero for any positive exponent.
+///
+/// - `base_1e18` - base, scaled by 1e18
+/// - `exponent_numerator` - numerator of the power to raise the base to
+/// - `exponent_denominator` - denominator of the power to raise the base to
+///
+/// Returns MathError in case of error
+pub fn pow_with_fraction(base_1e18: U256, exponent_numerator: u32, exponent_denominator: u32) -> Result<U256, MathError> {
+    if exponent_denominator == 0 {
+        return Err(ZeroInReserve);
+    }
+    if exponent_numerator == 0 {
+        return Ok(U256::from(FIXED_ONE));
+    }
+    if base_1e18.is_zero() || exponent_numerator == exponent_denominator {
+        return Ok(base_1e18);
+    }
+
+    let (negative, ln_base) = ln_series(base_1e18)?;
+    let exponent = ln_base
+        .checked_mul(U256::from(exponent_numerator)).ok_or(Overflow)?
+        / U256::from(exponent_denominator);
+
+    exp_series(negative, exponent)
+}
+
+/// Natural logarithm of a positive 1e18 fixed point number, as a sign and a 1e36 scaled magnitude.
+/// The argument is normalized to [1, 2) by a power of two and the rest is summed as 2 * atanh((m - 1) / (m + 1)).
+fn ln_series(x_1e18: U256) -> Result<(bool, U256), MathError> {
+    let one = U256::from(SERIES_ONE);
+    let x = x_1e18.checked_mul(U256::from(FIXED_ONE)).ok_or(Overflow)?;
+
+    let (negative, x) = match x < one {
+        true => (true, one * one / x),
+        false => (false, x),
+    };
+
+    let k = (x / one).bits() - 1;
+    let m = x >> k;
+
+    let z = (m - one) * one / (m + one);
+    let z_squared = z * z / one;
+    let mut term = z;
+    let mut sum = z;
+    let mut i = 1u64;
+    while !term.is_zero() {
+        term = term * z_squared / one;
+        sum += term / U256::from(2 * i + 1);
+        i += 1;
+    }
+
+    Ok((negative, U256::from(LN_2) * U256::from(k) + sum * 2))
+}
+
+/// Exponential of a 1e36 scaled magnitude with the given sign, scaled back to 1e18.
+/// The argument is reduced by multiples of ln(2) and the remainder is summed as a Taylor series.
+fn exp_series(negative: bool, y: U256) -> Result<U256, MathError> {
+    let one = U256::from(SERIES_ONE);
+    let ln_2 = U256::from(LN_2);
+
+    let k = y / ln_2;
+    let r = y - k * ln_2;
+
+    let mut term = one;
+    let mut sum = one;
+    let mut i = 1u64;
+    while !term.is_zero() {
+        term = term * r / one / U256::from(i);
+        sum += term;
+        i += 1;
+    }
+
+    let scale_down = U256::from(SERIES_ONE / FIXED_ONE);
+    match negative {
+        true if k >= U256::from(256) => Ok(U256::zero()),
+        true => Ok((one * one / sum / scale_down) >> k.low_u32()),
+        false => {
+            let value = sum / scale_down;
+            if k >= U256::from(256) || value.bits() + k.low_u64() as usize > 256 {
+                return Err(Overflow);
+            }
+            Ok(value << k.low_u32())
+        }
+    }
+}
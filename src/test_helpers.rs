@@ -0,0 +1,25 @@
+//! Assertions for downstream crates testing pools built on these calculations.
+
+use primitive_types::U256;
+
+type Balance = u128;
+
+/// Asserts that a reserve transition did not decrease the constant product invariant.
+///
+/// - `before` - (asset a reserve, asset b reserve) before the transition
+/// - `after` - (asset a reserve, asset b reserve) after the transition
+///
+/// Panics if `after.0 * after.1 < before.0 * before.1`
+pub fn assert_invariant_preserved(before: (Balance, Balance), after: (Balance, Balance)) {
+    let k_before = U256::from(before.0) * U256::from(before.1);
+    let k_after = U256::from(after.0) * U256::from(after.1);
+
+    assert!(
+        k_after >= k_before,
+        "constant product invariant violated: k decreased from {} ({:?}) to {} ({:?})",
+        k_before,
+        before,
+        k_after,
+        after
+    );
+}
@@ -1,5 +1,7 @@
 #![allow(unused_imports)]
-use crate::MathError::{ZeroInReserve, Overflow, InsufficientOutReserve};
+use primitive_types::U256;
+use crate::amm::SwapDirection::{Sell, Buy};
+use crate::MathError::{ZeroInReserve, Overflow, InsufficientOutReserve, InvalidTime, PrecisionLoss, Inexact, InsufficientLiquidity, KExceeded, SlippageExceeded, ExcessivePriceImpact, StaleReserves, PriceBoundBreached, InvalidFee, VolumeCapExceeded};
 
 #[test]
 fn spot_price_should_work() {
@@ -26,12 +28,15 @@ fn spot_price_should_work() {
 #[test]
 fn out_given_in_should_work() {
     let cases = vec![
-        (1000, 2000, 500, Ok(667), "Easy case"),
-        (0, u128::MAX, u128::MAX, Err(Overflow), "Zero sell reserve"),
+        (1000, 2000, 500, Ok(666), "Easy case"),
+        (1000, 2000, 1000, Ok(1000), "Exact division"),
+        (1000, 2000, 1, Ok(1), "Inexact division rounds down"),
+        (1000, 1, 1, Ok(0), "Output below one unit"),
+        (0, u128::MAX, u128::MAX, Ok(u128::MAX), "Zero sell reserve"),
         (0, 0, 0, Err(ZeroInReserve), "Zero reserves and weights"),
         (0, 1, 0, Err(ZeroInReserve), "Zero sell reserve and amount"),
-        (1, 0, 0, Ok(1), "Zero buy reserve and amount"),
-        (0, 0, u128::MAX, Ok(1), "Zero buy reserve and sell reserve"),
+        (1, 0, 0, Ok(0), "Zero buy reserve and amount"),
+        (0, 0, u128::MAX, Ok(0), "Zero buy reserve and sell reserve"),
     ];
 
     for case in cases {
@@ -48,6 +53,9 @@ fn out_given_in_should_work() {
 fn in_given_out_should_work() {
     let cases = vec![
         (2000, 1000, 500, Ok(334), "Easy case"),
+        (2000, 1000, 1000, Ok(1000), "Exact division"),
+        (2000, 1000, 1, Ok(1), "Inexact division rounds up"),
+        (2000, 1000, 0, Ok(0), "Zero amount"),
         (0, 0, 0, Err(ZeroInReserve), "Zero reserves and weights"),
         (0, 10, 1000, Err(InsufficientOutReserve), "amount cannot be > buy reserve"),
         (0, u128::MAX, u128::MAX, Err(InsufficientOutReserve), "div by zero"),
@@ -105,3 +113,2236 @@ fn remove_liquidity_should_work() {
         );
     }
 }
+
+#[test]
+fn interpolate_reserves_should_work() {
+    let cases = vec![
+        ((1000, 2000, 100), (2000, 1000, 200), 150, Ok((1500, 1500)), "Midpoint"),
+        ((1000, 2000, 100), (2000, 1000, 200), 100, Ok((1000, 2000)), "Start boundary"),
+        ((1000, 2000, 100), (2000, 1000, 200), 200, Ok((2000, 1000)), "End boundary"),
+        ((1000, 2000, 0), (1000, 2000, 3), 1, Ok((1000, 2000)), "Unchanged reserves"),
+        ((0, 10, 0), (10, 0, 3), 1, Ok((3, 7)), "Truncated result"),
+        ((1000, 2000, 100), (1000, 2000, 100), 100, Ok((1000, 2000)), "Single point range"),
+        ((1000, 2000, 100), (2000, 1000, 200), 99, Err(InvalidTime), "Before range"),
+        ((1000, 2000, 100), (2000, 1000, 200), 201, Err(InvalidTime), "After range"),
+        ((1000, 2000, 200), (2000, 1000, 100), 150, Err(InvalidTime), "Reversed snapshots"),
+        ((0, u128::MAX, 0), (u128::MAX, 0, 2), 1, Ok((u128::MAX / 2, u128::MAX - u128::MAX / 2)), "Max reserves"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::interpolate_reserves(case.0, case.1, case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+fn out_given_in_with_fee_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, (0, 1000), Ok(666), "Easy case without fee"),
+        (1000, 2000, 500, (3, 1000), Ok(665), "Easy case"),
+        (997, 2000, 1000, (3, 1000), Ok(1000), "Exact division"),
+        (1000, 2000, 500, (30, 10000), Ok(665), "Easy case in basis points"),
+        (1000, 2000, 1, (3, 1000), Ok(0), "Fee rounds the input down to zero"),
+        (1000, 2000, 500, (1000, 1000), Ok(0), "Whole amount taken as fee"),
+        (0, 0, 0, (3, 1000), Err(ZeroInReserve), "Zero reserves"),
+        (1000, 2000, 500, (1001, 1000), Err(InvalidFee), "Fee above whole amount"),
+        (1000, 2000, 500, (0, 0), Err(InvalidFee), "Zero fee denominator"),
+        (1, u128::MAX, u128::MAX, (3, 1000), Err(Overflow), "Overflow amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_with_fee(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn in_given_out_with_fee_should_work() {
+    let cases = vec![
+        (2000, 1000, 500, (0, 1000), Ok(334), "Easy case without fee"),
+        (2000, 1000, 500, (3, 1000), Ok(335), "Easy case"),
+        (2000, 997, 1000, (3, 1000), Ok(1000), "Exact division"),
+        (2000, 1000, 500, (100, 1000), Ok(371), "High fee"),
+        (0, 0, 0, (3, 1000), Err(ZeroInReserve), "Zero reserves"),
+        (0, 10, 1000, (3, 1000), Err(InsufficientOutReserve), "amount cannot be > buy reserve"),
+        (2000, 1000, 500, (1000, 1000), Err(InvalidFee), "Whole amount taken as fee"),
+        (2000, 1000, 500, (1001, 1000), Err(InvalidFee), "Fee above whole amount"),
+        (2000, 1000, 500, (3, 0), Err(InvalidFee), "Zero fee denominator"),
+        (u128::MAX, u128::MAX, u128::MAX - 1, (3, 1000), Err(Overflow), "Overflow weights"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_in_given_out_with_fee(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn zero_fee_should_match_fee_free_swaps() {
+    let cases = vec![
+        (1000, 2000, 500),
+        (1, 1, 0),
+        (u128::MAX, u128::MAX, u128::MAX / 2),
+        (1_000_000_007, 3, 999_999_937),
+    ];
+
+    for (in_reserve, out_reserve, amount) in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount, (0, 1000)),
+            crate::amm::calculate_out_given_in(in_reserve, out_reserve, amount)
+        );
+        assert_eq!(
+            crate::amm::calculate_in_given_out_with_fee(out_reserve, in_reserve, amount.min(out_reserve), (0, 1000)),
+            crate::amm::calculate_in_given_out(out_reserve, in_reserve, amount.min(out_reserve))
+        );
+    }
+}
+
+#[test]
+fn optimal_schedule_should_work() {
+    let cases = vec![
+        (vec![(1_000_000, 1_000_000), (1_000_000, 1_000_000)], 1000, 2, Ok(vec![vec![250, 250], vec![250, 250]]), "Identical pools"),
+        (vec![(1_000_000, 1_000_000), (1_000_000, 1_000_000)], 1001, 2, Ok(vec![vec![250, 251], vec![250, 250]]), "Uneven amount"),
+        (vec![(1_000_000, 4_000_000), (1_000_000, 1_000_000)], 1000, 1, Ok(vec![vec![1000, 0]]), "Shallow pool priced out"),
+        (vec![(4_000_000, 4_000_000), (1_000_000, 1_000_000)], 1000, 1, Ok(vec![vec![800, 200]]), "Split by depth"),
+        (vec![(1_000_000, 1_000_000), (0, 0)], 1000, 1, Ok(vec![vec![1000, 0]]), "Empty pool skipped"),
+        (vec![(1_000_000, 1_000_000)], 0, 1, Ok(vec![vec![0]]), "Zero amount"),
+        (vec![(1_000_000, 1_000_000)], 1000, 0, Err(ZeroInReserve), "Zero slices"),
+        (vec![], 1000, 2, Err(ZeroInReserve), "No pools"),
+        (vec![(0, 0)], 1000, 2, Err(ZeroInReserve), "Only empty pools"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::optimal_schedule(&case.0, case.1, case.2, (3, 1000)),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+
+    assert_eq!(crate::amm::optimal_schedule(&[(1_000_000, 1_000_000)], 1000, 2, (1000, 1000)), Err(InvalidFee), "Whole amount taken as fee");
+}
+
+#[test]
+fn optimal_schedule_should_route_exactly_the_total_amount() {
+    let pools = vec![(4_000_000, 4_000_000), (1_000_000, 1_000_000), (2_000_000, 3_000_000)];
+    let cases = vec![(1000, 3), (1001, 3), (1002, 3), (999_999, 7), (7, 10), (u128::MAX, 3)];
+
+    for (total_amount_in, slices) in cases {
+        let schedule = crate::amm::optimal_schedule(&pools, total_amount_in, slices, (3, 1000)).unwrap();
+        let slice_sums: Vec<u128> = schedule.iter().map(|split| split.iter().sum()).collect();
+        let (smallest, largest) = (*slice_sums.iter().min().unwrap(), *slice_sums.iter().max().unwrap());
+
+        assert_eq!(slice_sums.iter().try_fold(0u128, |acc, &sum| acc.checked_add(sum)), Some(total_amount_in), "{} over {}", total_amount_in, slices);
+        assert!(largest - smallest <= 1, "{} over {}", total_amount_in, slices);
+    }
+}
+
+#[test]
+fn optimal_schedule_should_beat_naive_routing() {
+    let pools = vec![(1_000_000, 1_000_000), (1_000_000, 1_000_000)];
+    let fee = (3, 1000);
+
+    let schedule = crate::amm::optimal_schedule(&pools, 200_000, 2, fee).unwrap();
+    let scheduled_out: u128 = schedule
+        .iter()
+        .flatten()
+        .zip(pools.iter().cycle())
+        .map(|(&amount, &(in_reserve, out_reserve))| {
+            crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount, fee).unwrap()
+        })
+        .sum();
+
+    let naive_out = crate::amm::calculate_out_given_in_with_fee(pools[0].0, pools[0].1, 200_000, fee).unwrap();
+
+    assert!(scheduled_out > naive_out, "{} <= {}", scheduled_out, naive_out);
+}
+
+#[test]
+fn realized_fee_on_output_should_work() {
+    let cases = vec![
+        (1_000_000_000_000_000_000, 1_000_000_000_000_000_000, 1_000_000, (3, 1000), Ok(3_000_003_000_003_000), "Small trade matches nominal fee"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, (3, 1000), Ok(2_728_016_731_002_728), "Large trade"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000, (3, 1000), Ok(1_502_253_382_000_000), "Trade as large as the pool"),
+        (1000, 2000, 500, (0, 1000), Ok(0), "Zero fee"),
+        (1000, 2000, 0, (3, 1000), Ok(0), "Zero amount"),
+        (1_000_000, 1000, 1, (3, 1000), Ok(0), "Output rounds to zero"),
+        (1000, 0, 500, (3, 1000), Ok(0), "Zero out reserve"),
+        (0, 0, 0, (3, 1000), Err(ZeroInReserve), "Zero reserves"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::realized_fee_on_output(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn out_given_in_precise_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 2_000_000_000_000_000, Ok(666), "Easy case"),
+        (1000, 2000, 500, 1_000_000_000_000_000, Err(PrecisionLoss), "Easy case with tight tolerance"),
+        (1000, 2000, 1000, 0, Ok(1000), "Exact division"),
+        (1_000_000_000_000_000_000_000_000_000_000, 1_000_000_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, Err(PrecisionLoss), "Tiny swap against huge reserves"),
+        (1_000_000_000_000_000_000_000_000_000_000, 1_000_000_000_000_000_000, 0, 0, Ok(0), "Zero amount"),
+        (0, 0, 0, 0, Err(ZeroInReserve), "Zero reserves"),
+        (1, u128::MAX, u128::MAX, 1_000_000_000_000_000_000, Ok(u128::MAX - 1), "Max reserves"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_precise(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn reserves_from_sqrt_price_should_work() {
+    let q96 = U256::one() << 96;
+    let one = U256::from(1_000_000_000_000_000_000u128);
+
+    let cases = vec![
+        (q96, one, Ok((1_000_000_000_000_000_000, 1_000_000_000_000_000_000)), "Price of 1"),
+        (q96 * 2, one, Ok((500_000_000_000_000_000, 2_000_000_000_000_000_000)), "Price of 4"),
+        (q96 / 2, one, Ok((2_000_000_000_000_000_000, 500_000_000_000_000_000)), "Price of 1/4"),
+        (U256::from_dec_str("112045541949572279837463876454").unwrap(), one, Ok((707_106_781_186_547_524, 1_414_213_562_373_095_048)), "Price of 2"),
+        (q96, U256::zero(), Ok((0, 0)), "Zero liquidity"),
+        (U256::zero(), one, Err(ZeroInReserve), "Zero price"),
+        (U256::one(), U256::from(u128::MAX), Err(Overflow), "Reserve a out of range"),
+        (q96, U256::MAX, Err(Overflow), "Overflow liquidity"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::reserves_from_sqrt_price(case.0, case.1),
+            case.2,
+            "{}",
+            case.3
+        );
+    }
+}
+
+#[test]
+fn reserves_from_sqrt_price_should_match_v3_relationships() {
+    // sqrt(3000) * 2^96, a typical ETH/USDC style price
+    let sqrt_price_x96 = U256::from_dec_str("4339505179874779489431521786241").unwrap();
+    let liquidity = U256::from(1_000_000_000_000_000_000u128);
+
+    let (reserve_a, reserve_b) = crate::amm::reserves_from_sqrt_price(sqrt_price_x96, liquidity).unwrap();
+
+    // Price within 1e-9 of 3000
+    let price = crate::amm::calculate_spot_price(reserve_a, reserve_b, 1_000_000_000).unwrap();
+    assert!((2_999_999_999_999..=3_000_000_000_001).contains(&price), "{}", price);
+
+    // Reserves multiply to liquidity squared within the rounding of both reserves
+    let k = U256::from(reserve_a) * U256::from(reserve_b);
+    let expected_k = liquidity * liquidity;
+    assert!(k <= expected_k && expected_k - k <= U256::from(reserve_a + reserve_b), "{}", k);
+}
+
+#[test]
+fn max_amount_in_for_target_price_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 2_000_000_000_000, 1_000_000_000_000_000_000, (3, 1000), Ok(415_459_942_199), "Easy case"),
+        (1_000_000_000_000, 1_000_000_000_000, 250_000_000_000_000_000, (0, 1000), Ok(1_000_000_000_000), "Quarter price without fee"),
+        (1000, 2000, 2_000_000_000_000_000_000, (3, 1000), Ok(0), "Already at target"),
+        (1000, 2000, 3_000_000_000_000_000_000, (3, 1000), Ok(0), "Target above spot price"),
+        (0, 2000, 1_000_000_000_000_000_000, (3, 1000), Err(ZeroInReserve), "Zero in reserve"),
+        (1000, 2000, 0, (3, 1000), Err(ZeroInReserve), "Zero target price"),
+        (1000, 2000, 1, (1000, 1000), Err(InvalidFee), "Whole amount taken as fee"),
+        (1000, 2000, 1, (3, 0), Err(InvalidFee), "Zero fee denominator"),
+        (u128::MAX, u128::MAX, 1, (3, 1000), Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::max_amount_in_for_target_price(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn cost_to_move_price_bps_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100, (3, 1000), Ok(5_002_569_821), "Easy case"),
+        (1_000_000_000_000, 1_000_000_000_000, 400, (3, 1000), Ok(19_863_493_197), "Larger move costs more"),
+        (4_000_000_000_000, 4_000_000_000_000, 100, (3, 1000), Ok(20_010_279_285), "Deeper pool costs more"),
+        (4_000_000_000_000, 4_000_000_000_000, 400, (3, 1000), Ok(79_453_972_792), "Deeper pool and larger move"),
+        (1_000_000_000_000, 1_000_000_000_000, 100, (0, 1000), Ok(4_987_562_112), "Zero fee"),
+        (1_000_000_000_000, 1_000_000_000_000, 0, (3, 1000), Ok(0), "Zero move"),
+        (0, 1_000_000_000_000, 100, (3, 1000), Err(ZeroInReserve), "Zero in reserve"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::cost_to_move_price_bps(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn position_value_after_price_move_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 10, 100, 2_000_000_000_000_000_000, Ok((70_710_678_118, 141_421_356_237)), "Price of a doubles"),
+        (1_000_000_000_000, 4_000_000_000_000, 50, 100, 500_000_000_000_000_000, Ok((707_106_781_186, 1_414_213_562_373)), "Price of a halves"),
+        (1_000_000_000_000, 1_000_000_000_000, 100, 100, 4_000_000_000_000_000_000, Ok((500_000_000_000, 2_000_000_000_000)), "Whole pool"),
+        (1_000_000_000_000, 1_000_000_000_000, 10, 100, 1_000_000_000_000_000_000, Ok((100_000_000_000, 100_000_000_000)), "No price move"),
+        (1_000_000_000_000, 1_000_000_000_000, 10, 0, 2_000_000_000_000_000_000, Err(ZeroInReserve), "Zero total shares"),
+        (1_000_000_000_000, 1_000_000_000_000, 10, 100, 0, Err(ZeroInReserve), "Zero price move"),
+        (u128::MAX, 1, 1, 1, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::position_value_after_price_move(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn out_given_in_exact_only_should_work() {
+    let cases = vec![
+        (1000, 2000, 1000, Ok(1000), "Exact division"),
+        (1000, 3000, 500, Ok(1000), "Exact division with uneven reserves"),
+        (1000, 2000, 500, Err(Inexact), "Inexact division"),
+        (1000, 2000, 0, Ok(0), "Zero amount"),
+        (0, 0, 0, Err(ZeroInReserve), "Zero reserves and amount"),
+        (0, u128::MAX, u128::MAX, Ok(u128::MAX), "Zero in reserve"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_exact_only(case.0, case.1, case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+fn max_burnable_shares_should_work() {
+    let cases = vec![
+        (1000, 2000, 100, 500, Ok(50), "Constraint binds on asset a"),
+        (2000, 1000, 100, 500, Ok(50), "Constraint binds on asset b"),
+        (1000, 2000, 100, 0, Ok(100), "Full burn allowed"),
+        (3, 3, 10, 1, Ok(9), "Rounded down withdrawal"),
+        (1000, 2000, 100, 1000, Ok(0), "Minimum equals reserve"),
+        (1000, 2000, 100, 1001, Ok(0), "Minimum above reserve"),
+        (0, 2000, 100, 0, Ok(100), "Zero reserve a"),
+        (1000, 2000, 0, 500, Err(ZeroInReserve), "Zero total shares"),
+        (u128::MAX, u128::MAX, u128::MAX, 0, Ok(u128::MAX), "Max values"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::max_burnable_shares(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn assert_invariant_preserved_should_accept_valid_swap() {
+    let amount_in = crate::amm::calculate_in_given_out(2000, 1000, 500).unwrap();
+    let amount_out = crate::amm::calculate_out_given_in(1000, 2000, 500).unwrap();
+
+    crate::test_helpers::assert_invariant_preserved((1000, 2000), (1000 + amount_in, 1500));
+    crate::test_helpers::assert_invariant_preserved((1000, 2000), (1500, 2000 - amount_out));
+    crate::test_helpers::assert_invariant_preserved((1000, 2000), (1000, 2000));
+    crate::test_helpers::assert_invariant_preserved((u128::MAX, u128::MAX), (u128::MAX, u128::MAX));
+}
+
+#[test]
+#[should_panic(expected = "constant product invariant violated")]
+fn assert_invariant_preserved_should_reject_decreased_k() {
+    crate::test_helpers::assert_invariant_preserved((1000, 2000), (1500, 1000));
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn harmonic_mean_price_should_work() {
+    let cases = vec![
+        (vec![1_000_000_000_000_000_000, 2_000_000_000_000_000_000], Ok(1_333_333_333_333_333_333), "Easy case"),
+        (vec![1_000_000_000_000_000_000, 2_000_000_000_000_000_000, 4_000_000_000_000_000_000], Ok(1_714_285_714_285_714_285), "Three prices"),
+        (vec![3, 3, 3], Ok(3), "Equal prices"),
+        (vec![5_000_000_000_000_000_000], Ok(5_000_000_000_000_000_000), "Single price"),
+        (vec![u128::MAX, u128::MAX], Ok(u128::MAX), "Max prices"),
+        (vec![1_000_000_000_000_000_000, 0], Err(ZeroInReserve), "Zero price"),
+        (vec![], Err(ZeroInReserve), "No prices"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::harmonic_mean_price(&case.0),
+            case.1,
+            "{}",
+            case.2
+        );
+    }
+}
+
+#[test]
+fn delta_neutral_hedge_should_work() {
+    let cases = vec![
+        (1000, 2000, 10, 100, 500_000_000_000_000_000, Ok(200), "Pool at the price shorts the asset b holding"),
+        (1000, 2000, 10, 100, 2_000_000_000_000_000_000, Ok(100), "Price of b rises"),
+        (1000, 2000, 10, 100, 125_000_000_000_000_000, Ok(400), "Price of b falls"),
+        (1000, 2000, 0, 100, 3_000_000_000_000_000_000, Ok(0), "Empty position"),
+        (1000, 2000, 10, 0, 2_000_000_000_000_000_000, Err(ZeroInReserve), "Zero total shares"),
+        (1000, 2000, 10, 100, 0, Err(ZeroInReserve), "Zero price"),
+        (u128::MAX, u128::MAX, 1, 1, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::delta_neutral_hedge(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn delta_neutral_hedge_should_match_the_position_value_derivative() {
+    let (reserve_a, reserve_b, shares, total_shares) = (1_000_000_000_000u128, 2_000_000_000_000u128, 25, 100);
+    let fixed_one = U256::from(1_000_000_000_000_000_000u128);
+    let k = U256::from(reserve_a) * U256::from(reserve_b);
+
+    // Value in asset a of the position once arbitrage moved the pool to the price of b, 2 * SQRT(K * PRICE) * SHARES / TOTAL_SHARES
+    let position_value = |price: U256| -> U256 { crate::math::integer_sqrt(k * price / fixed_one) * 2 * U256::from(shares) / U256::from(total_shares) };
+
+    let mut previous_hedge = u128::MAX;
+    for &price in [500_000_000_000_000_000u128, 2_000_000_000_000_000_000, 3_000_000_000_000_000_000, 10_000_000_000_000_000_000].iter() {
+        let hedge = crate::amm::delta_neutral_hedge(reserve_a, reserve_b, shares, total_shares, price).unwrap();
+
+        let step = U256::from(price / 1000);
+        let derivative = (position_value(U256::from(price) + step) - position_value(U256::from(price) - step)) * fixed_one / (step * 2);
+        let derivative = derivative.low_u128();
+
+        let difference = hedge.max(derivative) - hedge.min(derivative);
+        assert!(difference <= hedge / 100_000, "{} vs {}", hedge, derivative);
+        assert!(hedge > 0, "hedge should stay a short, the holding of asset b never changes sign");
+        assert!(hedge < previous_hedge, "hedge should shrink as the price rises");
+        previous_hedge = hedge;
+    }
+}
+
+#[test]
+fn lp_fees_earned_should_work() {
+    let cases = vec![
+        (U256::zero(), U256::from(2_000_000_000_000_000_000u128), 500, Ok(1000), "Easy case"),
+        (U256::from(1_000_000_000_000_000_000u128), U256::from(1_500_000_000_000_000_000u128), 3, Ok(1), "Truncated result"),
+        (U256::from(5), U256::from(5), 500, Ok(0), "No growth"),
+        (U256::from(6), U256::from(5), 500, Ok(0), "Growth decreased"),
+        (U256::zero(), U256::MAX, 2, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::lp_fees_earned(case.0, case.1, case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+fn lp_fees_earned_should_be_proportional_to_shares() {
+    let total_shares = 400;
+    let mut growth = U256::zero();
+
+    let growth_start = growth;
+    for &fee in [1000u128, 3000, 4000].iter() {
+        growth += U256::from(fee) * U256::from(1_000_000_000_000_000_000u128) / U256::from(total_shares);
+    }
+
+    let fees_small = crate::amm::lp_fees_earned(growth_start, growth, 100).unwrap();
+    let fees_large = crate::amm::lp_fees_earned(growth_start, growth, 300).unwrap();
+
+    assert_eq!(fees_small, 2000);
+    assert_eq!(fees_large, 6000);
+    assert_eq!(fees_small + fees_large, 8000);
+}
+
+#[test]
+fn is_ratio_safe_should_work() {
+    let (min_ratio, max_ratio) = crate::amm::max_safe_reserve_ratio();
+    let largest_pair = (u128::MAX, 79_228_162_551_157_825_758_142_922_760);
+    // Largest FEE_DEN - FEE_NUM of a nonzero fee
+    let worst_fee = (1, u32::MAX);
+
+    // Expected outcome of selling a whole reserve with the worst fee, in the direction that fails first
+    let cases = vec![
+        (1_000_000_000_000_000_000, 2_000_000_000_000_000_000, Ok(()), "Easy case"),
+        (min_ratio, max_ratio, Ok(()), "Max ratio"),
+        (max_ratio, min_ratio, Ok(()), "Inverse max ratio"),
+        (largest_pair.0, largest_pair.1, Ok(()), "Largest product"),
+        (largest_pair.0, largest_pair.1 + 1, Err(Overflow), "Product above envelope"),
+        (largest_pair.1 + 1, largest_pair.0, Err(Overflow), "Inverse product above envelope"),
+        (u128::MAX, u128::MAX, Err(Overflow), "Overflowing reserves"),
+        (0, 1000, Err(ZeroInReserve), "Zero reserve a"),
+        (1000, 0, Err(ZeroInReserve), "Zero reserve b"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::is_ratio_safe(case.0, case.1), case.2.is_ok(), "{}", case.3);
+
+        let swaps = [
+            crate::amm::calculate_out_given_in_with_fee(case.0, case.1, case.0, worst_fee),
+            crate::amm::calculate_out_given_in_with_fee(case.1, case.0, case.1, worst_fee),
+        ];
+        match case.2 {
+            Ok(()) => {
+                assert!(swaps.iter().all(|swap| swap.is_ok()), "{}", case.3);
+                assert!(crate::amm::calculate_out_given_in_with_fee(case.0, case.1, case.0, (3, 1000)).is_ok(), "{}", case.3);
+                assert!(crate::amm::calculate_out_given_in_with_fee(case.1, case.0, case.1, (3, 1000)).is_ok(), "{}", case.3);
+            }
+            Err(error) => assert!(swaps.contains(&Err(error)), "{}", case.3),
+        }
+    }
+}
+
+#[test]
+fn depth_to_price_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 1_210_000_000_000_000_000, Ok(100_000_000_000), "Easy case"),
+        (1_000_000_000_000, 1_000_000_000_000, 4_000_000_000_000_000_000, Ok(1_000_000_000_000), "Double in reserve"),
+        (1_000_000_000_000, 4_000_000_000_000, 1_000_000_000_000_000_000, Ok(1_000_000_000_000), "Uneven reserves"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, Ok(0), "Current price"),
+        (1_000_000_000_000, 1_000_000_000_000, 500_000_000_000_000_000, Ok(0), "Price below current"),
+        (0, 0, 1_000_000_000_000_000_000, Ok(0), "Zero reserves"),
+        (u128::MAX, u128::MAX, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::depth_to_price(case.0, case.1, case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn depth_chart_should_be_monotonic() {
+    let price_levels = vec![
+        1_000_000_000_000_000_000,
+        1_010_000_000_000_000_000,
+        1_210_000_000_000_000_000,
+        2_000_000_000_000_000_000,
+        4_000_000_000_000_000_000,
+    ];
+
+    let depths = crate::amm::depth_chart(1_000_000_000_000, 1_000_000_000_000, &price_levels).unwrap();
+
+    assert_eq!(depths.len(), price_levels.len());
+    assert_eq!(depths[0], 0);
+    assert_eq!(depths[2], 100_000_000_000);
+    assert_eq!(depths[4], 1_000_000_000_000);
+    assert!(depths.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", depths);
+
+    assert_eq!(crate::amm::depth_chart(u128::MAX, u128::MAX, &[u128::MAX]), Err(Overflow));
+}
+
+#[test]
+fn effective_price_with_gas_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 0, Ok(1_503_759_398_496_240_601), "Easy case without gas"),
+        (1000, 2000, 500, 100, Ok(1_654_135_338_345_864_661), "Easy case"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000, 0, Ok(1_003_010_033_109_361_192), "Swap only price"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000, 100_000, Ok(1_103_311_036_420_297_312), "Gas adds a tenth"),
+        (1_000_000_000_000, 1_000_000_000_000, 1000, 0, Ok(1_004_016_064_257_028_112), "Tiny trade swap only price"),
+        (1_000_000_000_000, 1_000_000_000_000, 1000, 100_000, Ok(101_405_622_489_959_839_357), "Gas dominates tiny trade"),
+        (0, 1000, 500, 100, Err(ZeroInReserve), "Zero in reserve"),
+        (u128::MAX, u128::MAX, u128::MAX, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::effective_price_with_gas(case.0, case.1, case.2, (3, 1000), case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn is_pool_routable_should_work() {
+    let cases = vec![
+        (1_000_000, 2_000_000, 1000, true, "Healthy pool"),
+        (1_000_000, 999, 1000, false, "Reserve b below minimum"),
+        (999, 1_000_000, 1000, false, "Reserve a below minimum"),
+        (10, 10, 1, false, "Dust pool"),
+        (1, 1_000_000, 1, true, "Shallow reserve in deep pool"),
+        (0, 1_000_000, 0, false, "Zero reserve"),
+        (0, 0, 0, false, "Empty pool"),
+        (u128::MAX, u128::MAX, u128::MAX, true, "Max reserves"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::is_pool_routable(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn partial_fill_out_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 950_000_000_000_000_000, Ok((49_622_551_862, 47_141_424_269)), "Price limit binds"),
+        (1_000_000_000_000, 1_000_000_000_000, 10_000_000_000, 950_000_000_000_000_000, Ok((10_000_000_000, 9_871_580_343)), "Input cap binds"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 0, Ok((100_000_000_000, 90_661_089_388)), "No price limit"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 1_500_000_000_000_000_000, Ok((0, 0)), "Price limit above spot price"),
+        (1_000_000_000_000, 1_000_000_000_000, 0, 950_000_000_000_000_000, Ok((0, 0)), "Zero input cap"),
+        (100, 100, 50, 900_000_000_000_000_000, Ok((10, 9)), "Floored output meets the limit exactly"),
+        (1000, 2000, 500, 1_500_000_000_000_000_000, Ok((330, 495)), "Largest amount past a sawtooth dip"),
+        (0, 0, 100, 0, Err(ZeroInReserve), "Zero reserves"),
+        (1, u128::MAX, u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::partial_fill_out(case.0, case.1, case.2, case.3, (3, 1000)),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn partial_fill_out_should_match_brute_force_maximum() {
+    let fixed_one = 1_000_000_000_000_000_000u128;
+
+    for &(in_reserve, out_reserve) in [(7u128, 13u128), (100, 100), (1000, 2000), (2000, 300)].iter() {
+        for &fee in [(3, 1000), (0, 1000), (1, 10)].iter() {
+            for &(price_numerator, price_denominator) in [(1, 1), (9, 10), (1, 2), (1, 10), (3, 2)].iter() {
+                let min_price = out_reserve * fixed_one / in_reserve * price_numerator / price_denominator;
+                let max_amount_in = 3 * in_reserve;
+
+                let within_price = |amount_in: u128| {
+                    let amount_out = crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee).unwrap();
+                    U256::from(amount_out) * U256::from(fixed_one) >= U256::from(amount_in) * U256::from(min_price)
+                };
+                let largest = (0..=max_amount_in).filter(|&amount_in| within_price(amount_in)).max().unwrap();
+                let expected_out = crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, largest, fee).unwrap();
+
+                assert_eq!(
+                    crate::amm::partial_fill_out(in_reserve, out_reserve, max_amount_in, min_price, fee),
+                    Ok((largest, expected_out)),
+                    "{} {} {:?} {}",
+                    in_reserve,
+                    out_reserve,
+                    fee,
+                    min_price
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn reserves_after_donation_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 0, Ok((1500, 2000)), "Single sided donation"),
+        (1000, 2000, 500, 1000, Ok((1500, 3000)), "Dual sided donation"),
+        (1000, 2000, 0, 0, Ok((1000, 2000)), "No donation"),
+        (0, 0, 500, 1000, Ok((500, 1000)), "Donation to empty pool"),
+        (u128::MAX, 2000, 1, 0, Err(Overflow), "Overflow reserve a"),
+        (1000, u128::MAX, 0, 1, Err(Overflow), "Overflow reserve b"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::reserves_after_donation(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+
+    // A donation raises the value of each share
+    let (reserve_a, reserve_b) = crate::amm::reserves_after_donation(1000, 2000, 500, 1000).unwrap();
+    assert_eq!(crate::amm::calculate_liquidity_out(reserve_a, reserve_b, 10, 100), Ok((150, 300)));
+}
+
+#[test]
+fn price_impact_should_work() {
+    let cases = vec![
+        (1000, 500, Ok(333_333_333_333_333_333), "Easy case"),
+        (1000, 1000, Ok(500_000_000_000_000_000), "Half the pool"),
+        (1_000_000_000_000, 1_000_000, Ok(999_999_000_000), "Tiny trade"),
+        (1000, 0, Ok(0), "Zero amount"),
+        (0, 1000, Ok(1_000_000_000_000_000_000), "Zero in reserve"),
+        (0, 0, Err(ZeroInReserve), "Zero reserve and amount"),
+        (u128::MAX, u128::MAX, Ok(500_000_000_000_000_000), "Max values"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_price_impact(case.0, case.1),
+            case.2,
+            "{}",
+            case.3
+        );
+    }
+}
+
+#[test]
+fn net_quote_should_match_individual_calculations() {
+    let fee = (3, 1000);
+    let cases = vec![(1000, 2000, 500), (1_000_000_000_000, 1_000_000_000_000, 1_000_000), (1000, 0, 500), (0, 1000, 500)];
+
+    for (in_reserve, out_reserve, amount_in) in cases {
+        assert_eq!(
+            crate::amm::net_quote(in_reserve, out_reserve, amount_in, fee),
+            Ok((
+                crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee).unwrap(),
+                crate::amm::calculate_price_impact(in_reserve, amount_in).unwrap(),
+            ))
+        );
+    }
+
+    assert_eq!(crate::amm::net_quote(1000, 2000, 500, fee), Ok((665, 333_333_333_333_333_333)));
+    assert_eq!(crate::amm::net_quote(0, 0, 0, fee), Err(ZeroInReserve));
+    assert_eq!(crate::amm::net_quote(1, u128::MAX, u128::MAX, fee), Err(Overflow));
+}
+
+#[test]
+fn price_from_reserves_should_work() {
+    let cases = vec![
+        (1000, 2000, Ok(2_000_000_000_000_000_000), "Easy case"),
+        (3, 1, Ok(333_333_333_333_333_333), "Truncated result"),
+        (1000, 0, Ok(0), "Zero reserve b"),
+        (0, 1000, Err(ZeroInReserve), "Zero reserve a"),
+        (1, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::price_from_reserves(case.0, case.1), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+fn seed_reserves_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 2_000_000_000_000_000_000, Ok((1_000_000_000_000, 500_000_000_000)), "Easy case"),
+        (1_000_000_000_000, 1_000_000_000_000_000_000, Ok((1_000_000_000_000, 1_000_000_000_000)), "Equal price"),
+        (1_000_000_000_000, 3_000_000_000_000_000_000, Ok((1_000_000_000_000, 333_333_333_333)), "Truncated result"),
+        (0, 2_000_000_000_000_000_000, Ok((0, 0)), "Zero value"),
+        (1_000_000_000_000, 0, Err(ZeroInReserve), "Zero price"),
+        (u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::seed_reserves(case.0, case.1), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+fn seed_reserves_should_match_oracle_price() {
+    let target_value_a = 1_000_000_000_000_000;
+
+    for &oracle_price in [2_000_000_000_000_000_000, 3_000_000_000_000_000_000, 123_456_789_000_000_000, 1_000_000_000_000_000_000].iter() {
+        let (reserve_a, reserve_b) = crate::amm::seed_reserves(target_value_a, oracle_price).unwrap();
+        let pool_price = crate::amm::price_from_reserves(reserve_b, reserve_a).unwrap();
+
+        // Rounding reserve b down moves the price by at most one unit of reserve b
+        let tolerance = oracle_price / reserve_b + 1;
+        assert!(pool_price >= oracle_price && pool_price - oracle_price <= tolerance, "{} != {}", pool_price, oracle_price);
+    }
+}
+
+#[test]
+fn shares_minted_safe_should_work() {
+    let cases = vec![
+        (1000, 2000, 100, 200, 500, Ok(50), "Proportional deposit"),
+        (1000, 2000, 100, 1000, 500, Ok(50), "Over-supplied asset b"),
+        (1000, 2000, 500, 200, 500, Ok(50), "Over-supplied asset a"),
+        (1000, 2000, 100, 0, 500, Ok(0), "Single sided deposit"),
+        (3, 3, 1, 1, 10, Ok(3), "Truncated result"),
+        (0, 2000, 100, 200, 500, Err(ZeroInReserve), "Zero reserve a"),
+        (1000, 0, 100, 200, 500, Err(ZeroInReserve), "Zero reserve b"),
+        (1, 1, u128::MAX, u128::MAX, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_shares_minted_safe(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn pool_utilization_should_work() {
+    let cases = vec![
+        (100, 10_000, Ok(10_000_000_000_000_000), "Low turnover"),
+        (50_000, 10_000, Ok(5_000_000_000_000_000_000), "High turnover"),
+        (1, 3, Ok(333_333_333_333_333_333), "Truncated result"),
+        (0, 10_000, Ok(0), "No volume"),
+        (100, 0, Err(ZeroInReserve), "Zero reserve"),
+        (u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::pool_utilization(case.0, case.1), case.2, "{}", case.3);
+    }
+
+    let base = crate::amm::pool_utilization(1000, 10_000).unwrap();
+    for multiplier in 1..10 {
+        assert_eq!(crate::amm::pool_utilization(1000 * multiplier, 10_000), Ok(base * multiplier));
+    }
+}
+
+#[test]
+fn best_split_across_tiers_should_work() {
+    let shallow_low_fee = (1_000_000_000_000, 1_000_000_000_000, (1, 1000));
+    let deep_high_fee = (10_000_000_000_000, 10_000_000_000_000, (10, 1000));
+
+    let cases = vec![
+        (shallow_low_fee, deep_high_fee, 1_000_000_000, Ok((1_000_000_000, 0, 998_002_995)), "Small trade stays in low fee pool"),
+        (shallow_low_fee, deep_high_fee, 100_000_000_000, Ok((13_182_286_193, 86_817_713_807, 98_215_032_424)), "Medium trade"),
+        (shallow_low_fee, deep_high_fee, 1_000_000_000_000, Ok((94_664_526_316, 905_335_473_684, 908_956_849_476)), "Large trade"),
+        (shallow_low_fee, deep_high_fee, 0, Ok((0, 0, 0)), "Zero amount"),
+        (shallow_low_fee, (0, 0, (10, 1000)), 1_000_000_000, Ok((1_000_000_000, 0, 998_002_995)), "Empty high fee pool"),
+        ((0, 0, (1, 1000)), (0, 0, (10, 1000)), 1_000_000_000, Err(ZeroInReserve), "Empty pools"),
+        (shallow_low_fee, (u128::MAX, u128::MAX, (10, 1000)), 1_000_000_000, Err(Overflow), "Overflow"),
+        (shallow_low_fee, (10_000_000_000_000, 10_000_000_000_000, (1001, 1000)), 1_000_000_000, Err(InvalidFee), "Fee above whole amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::best_split_across_tiers(case.0, case.1, case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+fn best_split_across_tiers_should_beat_single_tier() {
+    let (in_low, out_low, fee_low) = (1_000_000_000_000, 1_000_000_000_000, (1, 1000));
+    let (in_high, out_high, fee_high) = (10_000_000_000_000, 10_000_000_000_000, (10, 1000));
+
+    for &amount_in in [100_000_000_000, 500_000_000_000, 1_000_000_000_000].iter() {
+        let (_, _, total_out) =
+            crate::amm::best_split_across_tiers((in_low, out_low, fee_low), (in_high, out_high, fee_high), amount_in)
+                .unwrap();
+
+        let low_only = crate::amm::calculate_out_given_in_with_fee(in_low, out_low, amount_in, fee_low).unwrap();
+        let high_only = crate::amm::calculate_out_given_in_with_fee(in_high, out_high, amount_in, fee_high).unwrap();
+
+        assert!(total_out > low_only && total_out > high_only, "{} {} {}", total_out, low_only, high_only);
+    }
+}
+
+#[test]
+fn apply_slippage_down_should_work() {
+    let cases = vec![
+        (1000, (5, 1000), Ok(995), "Easy case"),
+        (999, (1, 100), Ok(989), "Truncated result"),
+        (1000, (0, 1000), Ok(1000), "Zero slippage"),
+        (1000, (1000, 1000), Ok(0), "Full slippage"),
+        (1000, (1001, 1000), Err(InvalidFee), "Slippage above amount"),
+        (1000, (0, 0), Err(InvalidFee), "Zero denominator"),
+        (u128::MAX, (1, 2), Ok(u128::MAX / 2), "Max amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::apply_slippage_down(case.0, case.1), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+fn twap_execution_output_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 1, Ok(90_661_089_388), "Single slice"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 4, Ok(97_275_410_392), "Four slices"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_003, 4, Ok(97_275_410_395), "Uneven slices"),
+        (1000, 2000, 500, 3, Ok(854), "Easy case"),
+        (1000, 2000, 2, 3, Ok(0), "Fewer units than slices"),
+        (1000, 2000, 0, 3, Ok(0), "Zero amount"),
+        (1000, 2000, 500, 0, Err(ZeroInReserve), "Zero slices"),
+        (1, u128::MAX, u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::twap_execution_output(case.0, case.1, case.2, case.3, (3, 1000)),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn twap_execution_output_should_match_explicit_slices() {
+    let (in_reserve, out_reserve, fee) = (1_000_000_000_000, 3_000_000_000_000, (3, 1000));
+    let cases = vec![(100_000_000_000u128, 4u32), (100_000_000_003, 4), (999_999_999, 7), (5, 10), (u128::MAX - 1_000_000_000_000, 3)];
+
+    for (total_amount_in, slices) in cases {
+        let slice_amount = total_amount_in / u128::from(slices);
+        let remainder = total_amount_in % u128::from(slices);
+
+        let expected: u128 = (0..u128::from(slices))
+            .map(|slice| if slice < remainder { slice_amount + 1 } else { slice_amount })
+            .map(|amount_in| crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee).unwrap())
+            .sum();
+
+        assert_eq!(crate::amm::twap_execution_output(in_reserve, out_reserve, total_amount_in, slices, fee), Ok(expected), "{} over {}", total_amount_in, slices);
+    }
+}
+
+#[test]
+fn twap_min_received_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 1, (5, 1000), Ok(90_207_783_941), "Single slice"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, 4, (5, 1000), Ok(96_789_033_340), "Four slices"),
+        (1000, 2000, 500, 3, (1, 100), Ok(845), "Easy case"),
+        (1000, 2000, 500, 3, (0, 100), Ok(854), "Zero slippage"),
+        (1000, 2000, 500, 0, (1, 100), Err(ZeroInReserve), "Zero slices"),
+        (1000, 2000, 500, 3, (101, 100), Err(InvalidFee), "Slippage above amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::twap_min_received(case.0, case.1, case.2, case.3, case.4, (3, 1000)),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn twap_min_received_should_be_below_expected_by_cumulative_slippage() {
+    let (in_reserve, out_reserve, fee, slippage) = (1_000_000_000_000, 1_000_000_000_000, (3, 1000), (5, 1000));
+
+    for &slices in [1, 2, 5, 10].iter() {
+        let expected = crate::amm::twap_execution_output(in_reserve, out_reserve, 100_000_000_000, slices, fee).unwrap();
+        let minimum =
+            crate::amm::twap_min_received(in_reserve, out_reserve, 100_000_000_000, slices, slippage, fee).unwrap();
+
+        // Every slice rounds its reduced output down by less than one unit
+        let cumulative_slippage = expected * 5 / 1000;
+        assert!(minimum < expected, "{}", slices);
+        assert!(expected - minimum >= cumulative_slippage, "{}", slices);
+        assert!(expected - minimum <= cumulative_slippage + u128::from(slices), "{}", slices);
+    }
+}
+
+#[test]
+fn arbitrage_amount_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 2_000_000_000_000, 2_000_000_000_000_000_000, Ok((Sell, 0)), "Already at external price"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, Ok((Sell, 0)), "Balanced pool at external price"),
+        (0, 2_000_000_000_000, 2_000_000_000_000_000_000, Err(ZeroInReserve), "Zero reserve in"),
+        (1_000_000_000_000, 0, 2_000_000_000_000_000_000, Err(ZeroInReserve), "Zero reserve out"),
+        (1_000_000_000_000, 2_000_000_000_000, 0, Err(ZeroInReserve), "Zero external price"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::arbitrage_amount(case.0, case.1, case.2, (3, 1000)),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+fn sync_to_external_price_should_match_external_price() {
+    let (reserve_in, reserve_out, fee) = (1_000_000_000_000, 2_000_000_000_000, (3, 1000));
+
+    let cases = vec![
+        (1_500_000_000_000_000_000, Sell, "Pool price above external price"),
+        (1_000_000_000_000_000_000, Sell, "Pool price far above external price"),
+        (2_500_000_000_000_000_000, Buy, "Pool price below external price"),
+        (4_000_000_000_000_000_000, Buy, "Pool price far below external price"),
+    ];
+
+    for (external_price, expected_direction, description) in cases {
+        let (direction, amount_in, amount_out) =
+            crate::amm::sync_to_external_price(reserve_in, reserve_out, external_price, fee).unwrap();
+
+        let (new_in, new_out) = match direction {
+            Sell => (reserve_in + amount_in, reserve_out - amount_out),
+            Buy => (reserve_in - amount_out, reserve_out + amount_in),
+        };
+        let new_price = crate::amm::price_from_reserves(new_in, new_out).unwrap();
+
+        assert_eq!(direction, expected_direction, "{}", description);
+        assert!(amount_in > 0 && amount_out > 0, "{}", description);
+        assert!(
+            new_price.max(external_price) - new_price.min(external_price) <= external_price / 1_000_000_000,
+            "{}: {} != {}",
+            description,
+            new_price,
+            external_price
+        );
+    }
+}
+
+#[test]
+fn pow_fixed_should_work() {
+    let one = 1_000_000_000_000_000_000u128;
+    let cases = vec![
+        (2 * one, 10, Ok(U256::from(1024 * one)), "Power of two"),
+        (1_500_000_000_000_000_000, 3, Ok(U256::from(3_375_000_000_000_000_000u128)), "Fractional base"),
+        (1_010_000_000_000_000_000, 100, Ok(U256::from(2_704_813_829_421_526_079u128)), "Compounding base"),
+        (500_000_000_000_000_000, 1, Ok(U256::from(500_000_000_000_000_000u128)), "Power of one"),
+        (123, 0, Ok(U256::from(one)), "Power of zero"),
+        (0, 5, Ok(U256::zero()), "Zero base"),
+        (u128::MAX, 3, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::math::pow_fixed(U256::from(case.0), case.1), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+fn integer_sqrt_should_work() {
+    let max_square = U256::from(u128::MAX) * U256::from(u128::MAX);
+    let cases = vec![
+        (U256::zero(), U256::zero(), "Zero"),
+        (U256::one(), U256::one(), "One"),
+        (U256::from(2), U256::one(), "Two"),
+        (U256::from(3), U256::one(), "Below a perfect square"),
+        (U256::from(4), U256::from(2), "Perfect square"),
+        (U256::from(1_000_000_000_000u128 - 1), U256::from(999_999), "One below a large perfect square"),
+        (U256::from(1_000_000_000_000u128), U256::from(1_000_000), "Large perfect square"),
+        (U256::from(1_000_000_000_000u128 + 1), U256::from(1_000_000), "One above a large perfect square"),
+        (U256::from(u128::MAX), U256::from(u64::MAX), "Max u128"),
+        (max_square - 1, U256::from(u128::MAX - 1), "One below the max square"),
+        (max_square, U256::from(u128::MAX), "Max square"),
+        (U256::max_value(), U256::from(u128::MAX), "Max U256"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::math::integer_sqrt(case.0), case.1, "{}", case.2);
+    }
+}
+
+#[test]
+fn integer_sqrt_should_bound_the_root() {
+    let mut inputs = vec![U256::max_value(), U256::from(u128::MAX) * U256::from(u128::MAX)];
+    for bits in 1..256 {
+        let power = U256::one() << bits;
+        inputs.extend_from_slice(&[power - 1, power, power + 1]);
+    }
+
+    for n in inputs {
+        let root = crate::math::integer_sqrt(n);
+        assert!(root * root <= n, "{}", n);
+        assert!((root + 1).full_mul(root + 1) > n.into(), "{}", n);
+    }
+}
+
+#[test]
+fn pow_with_fraction_should_work() {
+    let one = 1_000_000_000_000_000_000u128;
+    let cases = vec![
+        (500_000_000_000_000_000, 1, 4, Ok(U256::from(840_896_415_253_714_543u128)), "Fourth root below one"),
+        (2 * one, 1, 4, Ok(U256::from(1_189_207_115_002_721_066u128)), "Fourth root above one"),
+        (2 * one, 4, 1, Ok(U256::from(16 * one)), "Integer power"),
+        (900_000_000_000_000_000, 100, 1, Ok(U256::from(26_561_398_887_587u128)), "Large exponent"),
+        (123_456_789_000_000_000, 7, 3, Ok(U256::from(7_589_298_127_660_086u128)), "Fractional exponent"),
+        (1, 1, 4, Ok(U256::from(31_622_776_601_683u128)), "Smallest base"),
+        (500_000_000_000_000_000, 3, 3, Ok(U256::from(500_000_000_000_000_000u128)), "Power of one"),
+        (123, 0, 5, Ok(U256::from(one)), "Power of zero"),
+        (0, 1, 4, Ok(U256::zero()), "Zero base"),
+        (one, 1, 0, Err(ZeroInReserve), "Zero exponent denominator"),
+        (u128::MAX, 3, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::math::pow_with_fraction(U256::from(case.0), case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn compounded_shares_should_work() {
+    let year = 365 * 24 * 60 * 60;
+    let cases = vec![
+        (1000, 0, 12, year / 12, Ok(1000), "Zero apr"),
+        (1_000_000_000_000_000_000, 100_000_000_000_000_000, 1, year, Ok(1_100_000_000_000_000_000), "Single yearly period"),
+        (1_000_000_000_000_000_000, 100_000_000_000_000_000, 12, year / 12, Ok(1_104_713_067_441_297_231), "Monthly periods"),
+        (1_000_000_000_000_000_000, 100_000_000_000_000_000, 365, 86_400, Ok(1_105_155_781_616_264_095), "Daily periods"),
+        (1000, 100_000_000_000_000_000, 0, year, Ok(1000), "Zero periods"),
+        (u128::MAX, 100_000_000_000_000_000, 1, year, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::compounded_shares(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+
+    // Compounding beats simple interest of 10% over a year
+    let compounded = crate::amm::compounded_shares(1_000_000_000_000, 100_000_000_000_000_000, 12, year / 12).unwrap();
+    assert!(compounded > 1_100_000_000_000, "{}", compounded);
+}
+
+#[test]
+fn min_economical_swap_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000, Ok(1_003_012), "Deep pool"),
+        (1_000_000_000, 1_000_000_000, 1_000_000, Ok(1_004_015), "Shallow pool"),
+        (10_000_000, 10_000_000, 1_000_000, Ok(1_114_456), "Very shallow pool"),
+        (1000, 2000, 0, Ok(2), "No gas"),
+        (1000, 2000, 1998, Ok(2_005_016), "Gas close to reserve"),
+        (1000, 2000, 1999, Err(Overflow), "No economical amount"),
+        (0, 2000, 100, Err(ZeroInReserve), "Zero in reserve"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::min_economical_swap(case.0, case.1, (3, 1000), case.2),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+
+    assert_eq!(crate::amm::min_economical_swap(1000, 2000, (1000, 1000), 100), Err(InvalidFee), "Whole amount taken as fee");
+
+    let threshold = crate::amm::min_economical_swap(1_000_000_000, 1_000_000_000, (3, 1000), 1_000_000).unwrap();
+    assert!(crate::amm::calculate_out_given_in_with_fee(1_000_000_000, 1_000_000_000, threshold, (3, 1000)).unwrap() > 1_000_000);
+    assert!(crate::amm::calculate_out_given_in_with_fee(1_000_000_000, 1_000_000_000, threshold - 1, (3, 1000)).unwrap() <= 1_000_000);
+
+    // Tiny amounts lose their whole output to the fee, so the threshold is checked against every small amount
+    for &(in_reserve, out_reserve) in [(1000u128, 2000u128), (10, 10), (7, 300), (300, 7), (1, 1000)].iter() {
+        for &fee in [(3, 1000), (1, 2), (0, 1000)].iter() {
+            for gas in 0..out_reserve.min(20) {
+                let economical = |amount_in: u128| crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee).unwrap() > gas;
+                let smallest = (1..=100_000).find(|&amount_in| economical(amount_in));
+                let threshold = crate::amm::min_economical_swap(in_reserve, out_reserve, fee, gas);
+                match smallest {
+                    Some(amount_in) => assert_eq!(threshold, Ok(amount_in), "{} {} {:?} {}", in_reserve, out_reserve, fee, gas),
+                    None => assert!(!matches!(threshold, Ok(amount_in) if amount_in <= 100_000), "{} {} {:?} {}", in_reserve, out_reserve, fee, gas),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn reconcile_reserves_should_work() {
+    let cases = vec![
+        (1000, 2000, 1003, 2001, Ok((1003, 2001)), "Positive dust"),
+        (1000, 2000, 1000, 2000, Ok((1000, 2000)), "No dust"),
+        (1000, 2000, 1000, 2005, Ok((1000, 2005)), "Dust in asset b only"),
+        (1000, 2000, 999, 2001, Err(InsufficientLiquidity), "Shortfall in asset a"),
+        (1000, 2000, 1001, 1999, Err(InsufficientLiquidity), "Shortfall in asset b"),
+        (0, 0, u128::MAX, u128::MAX, Ok((u128::MAX, u128::MAX)), "Max dust"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::reconcile_reserves(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn no_arb_band_should_work() {
+    let cases = vec![
+        (1000, 2000, (3, 1000), Ok((1_994_000_000_000_000_000, 2_006_018_054_162_487_462)), "Easy case"),
+        (1000, 2000, (10, 1000), Ok((1_980_000_000_000_000_000, 2_020_202_020_202_020_202)), "Higher fee"),
+        (1000, 2000, (0, 1000), Ok((2_000_000_000_000_000_000, 2_000_000_000_000_000_000)), "Zero fee"),
+        (1000, 0, (3, 1000), Ok((0, 0)), "Zero out reserve"),
+        (0, 2000, (3, 1000), Err(ZeroInReserve), "Zero in reserve"),
+        (1000, 2000, (1000, 1000), Err(InvalidFee), "Whole amount taken as fee"),
+        (1000, 2000, (1001, 1000), Err(InvalidFee), "Fee above whole amount"),
+        (1, u128::MAX, (3, 1000), Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::no_arb_band(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+
+    let spot_price = crate::amm::price_from_reserves(1000, 2000).unwrap();
+    let (lower, upper) = crate::amm::no_arb_band(1000, 2000, (3, 1000)).unwrap();
+    let (wider_lower, wider_upper) = crate::amm::no_arb_band(1000, 2000, (10, 1000)).unwrap();
+    assert!(wider_lower < lower && lower < spot_price && spot_price < upper && upper < wider_upper);
+}
+
+#[test]
+fn out_given_in_capped_k_should_work() {
+    let k = U256::from(1_000_000_000_000u128) * U256::from(1_000_000_000_000u128);
+    let max_k = k + U256::from(10_000_000_000_000_000u128);
+
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000, max_k, Ok(996_999), "Small swap within cap"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, max_k, Err(KExceeded), "Large swap trips cap"),
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, U256::MAX, Ok(90_661_089_388), "No cap"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000, k, Err(KExceeded), "Cap at current k"),
+        (1, 0, 0, U256::MAX, Ok(0), "Zero out reserve and amount"),
+        (0, 0, 0, U256::MAX, Err(ZeroInReserve), "Zero reserves"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_capped_k(case.0, case.1, case.2, (3, 1000), case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn lp_share_price_should_work() {
+    let cases = vec![
+        (1000, 2000, 100, 2_000_000_000_000_000_000, Ok(40_000_000_000_000_000_000), "Easy case"),
+        (1000, 1000, 3, 1_000_000_000_000_000_000, Ok(666_666_666_666_666_666_666), "Truncated result"),
+        (1000, 2000, 100, 0, Ok(20_000_000_000_000_000_000), "Worthless asset a"),
+        (0, 0, 100, 2_000_000_000_000_000_000, Ok(0), "Empty pool"),
+        (1000, 2000, 0, 2_000_000_000_000_000_000, Err(ZeroInReserve), "Zero total shares"),
+        (u128::MAX, u128::MAX, 1, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::lp_share_price(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn share_value_delta_should_work() {
+    let cases = vec![
+        (1000, 1000, 100, 1_000_000_000_000_000_000, 10_000_000_000_000_000, Ok(100_000_000_000_000_000), "Balanced pool"),
+        (4000, 250, 100, 1_000_000_000_000_000_000, 10_000_000_000_000_000, Ok(400_000_000_000_000_000), "Imbalanced pool"),
+        (1000, 1000, 100, 1_000_000_000_000_000_000, 0, Ok(0), "No price change"),
+        (0, 1000, 100, 1_000_000_000_000_000_000, 10_000_000_000_000_000, Ok(0), "No asset a"),
+        (1000, 1000, 0, 1_000_000_000_000_000_000, 10_000_000_000_000_000, Err(ZeroInReserve), "Zero total shares"),
+        (1000, 1000, 100, u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::share_value_delta(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+
+    // Sensitivity grows as the pool holds more of the moving asset
+    let mut previous = 0;
+    for &reserve_a in [1000, 2000, 4000, 8000].iter() {
+        let delta =
+            crate::amm::share_value_delta(reserve_a, 4_000_000 / reserve_a, 100, 1_000_000_000_000_000_000, 10_000_000_000_000_000)
+                .unwrap();
+        assert!(delta > previous, "{}", reserve_a);
+        previous = delta;
+    }
+
+    // The static delta of a balanced pool is its asset a holding, while the value of the pool rebalanced along the curve,
+    // 2 * SQRT(K * PRICE) per share, only departs from it by the second order term, near zero around price 1
+    let (reserve, total_shares, price, delta_price) = (1_000_000_000_000u128, 1_000_000u128, 1_000_000_000_000_000_000u128, 10_000_000_000_000_000u128);
+    let fixed_one = U256::from(1_000_000_000_000_000_000u128);
+    let curve_value = |price: u128| -> U256 {
+        crate::math::integer_sqrt(U256::from(reserve) * U256::from(reserve) * U256::from(price) / fixed_one) * 2 * fixed_one / U256::from(total_shares)
+    };
+    let curve_delta = (curve_value(price + delta_price) - curve_value(price)).low_u128();
+    let static_delta = crate::amm::share_value_delta(reserve, reserve, total_shares, price, delta_price).unwrap();
+    assert_eq!(static_delta, reserve * delta_price / total_shares);
+    assert!(static_delta - curve_delta <= static_delta / 100, "{} vs {}", static_delta, curve_delta);
+}
+
+#[test]
+fn cost_breakdown_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000, (3, 1000), Ok((248_001_521, 9_090_909_091)), "Large trade"),
+        (1_000_000_000_000, 2_000_000_000_000, 1_000_000_000, (3, 1000), Ok((5_988_036, 1_998_002)), "Small trade"),
+        (1000, 2000, 500, (3, 1000), Ok((1, 334)), "Easy case"),
+        (1000, 2000, 500, (0, 1000), Ok((0, 334)), "Zero fee"),
+        (0, 2000, 500, (3, 1000), Err(ZeroInReserve), "Zero in reserve"),
+        (1, u128::MAX, u128::MAX, (3, 1000), Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::cost_breakdown(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn cost_breakdown_should_sum_to_total_cost() {
+    let fee = (3, 1000);
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 100_000_000_000),
+        (1_000_000_000_000, 2_000_000_000_000, 1_000_000_000),
+        (1_000_000, 3_000_000, 1_234_567),
+        (1000, 2000, 500),
+        (1000, 2000, 1),
+    ];
+
+    for (in_reserve, out_reserve, amount_in) in cases {
+        let (fee_cost, slippage_cost) = crate::amm::cost_breakdown(in_reserve, out_reserve, amount_in, fee).unwrap();
+
+        let ideal_out = crate::amm::calculate_spot_price(in_reserve, out_reserve, amount_in).unwrap();
+        let amount_out = crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount_in, fee).unwrap();
+
+        assert_eq!(fee_cost + slippage_cost, ideal_out.saturating_sub(amount_out), "{}", amount_in);
+    }
+}
+
+#[test]
+fn reserves_after_burn_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 2500, Ok((800, 1600)), "Easy case"),
+        (1000, 2000, 2500, 2500, Ok((0, 0)), "Burn all shares"),
+        (1000, 2000, 0, 2500, Ok((1000, 2000)), "Burn nothing"),
+        (10, 10, 1, 3, Ok((7, 7)), "Rounded down withdrawal"),
+        (1000, 2000, 3000, 2500, Err(InsufficientLiquidity), "Burn more than total shares"),
+        (1000, 2000, 500, 0, Err(ZeroInReserve), "Zero total shares"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::reserves_after_burn(case.0, case.1, case.2, case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+}
+
+#[test]
+fn reserves_after_burn_should_complement_liquidity_out() {
+    let cases = vec![(1000, 2000, 500, 2500), (10, 10, 1, 3), (123_456, 654_321, 777, 9999), (u128::MAX, 1, 1, 2)];
+
+    for (reserve_a, reserve_b, shares, total_shares) in cases {
+        let (withdrawn_a, withdrawn_b) =
+            crate::amm::calculate_liquidity_out(reserve_a, reserve_b, shares, total_shares).unwrap();
+        let (remaining_a, remaining_b) =
+            crate::amm::reserves_after_burn(reserve_a, reserve_b, shares, total_shares).unwrap();
+
+        assert_eq!(withdrawn_a + remaining_a, reserve_a);
+        assert_eq!(withdrawn_b + remaining_b, reserve_b);
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn worst_price_in_batch_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, vec![10_000_000_000, 50_000_000_000, 10_000_000_000], Ok(879_208_854_300_000_000), "Largest fill first"),
+        (1_000_000_000_000, 1_000_000_000_000, vec![10_000_000_000; 3], Ok(949_065_278_300_000_000), "Equal fills"),
+        (1000, 2000, vec![500], Ok(1_330_000_000_000_000_000), "Single swap"),
+        (1000, 2000, vec![0, 500, 0], Ok(1_330_000_000_000_000_000), "Zero amounts skipped"),
+        (1000, 2000, vec![], Err(ZeroInReserve), "Empty batch"),
+        (0, 0, vec![500], Ok(0), "Empty pool"),
+        (1, u128::MAX, vec![u128::MAX], Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::worst_price_in_batch(case.0, case.1, &case.2, (3, 1000)),
+            case.3,
+            "{}",
+            case.4
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn worst_price_in_batch_should_match_cumulative_fill() {
+    let (in_reserve, out_reserve, fee) = (1_000_000_000_000, 1_000_000_000_000, (3, 1000));
+    let amounts = vec![30_000_000_000, 10_000_000_000, 20_000_000_000];
+
+    let worst = crate::amm::worst_price_in_batch(in_reserve, out_reserve, &amounts, fee).unwrap();
+
+    // The last fill trades against reserves moved by all earlier fills
+    let mut reserves = (in_reserve, out_reserve);
+    for &amount_in in &amounts[..amounts.len() - 1] {
+        let amount_out = crate::amm::calculate_out_given_in_with_fee(reserves.0, reserves.1, amount_in, fee).unwrap();
+        reserves = (reserves.0 + amount_in, reserves.1 - amount_out);
+    }
+    let last_amount = amounts[amounts.len() - 1];
+    let last_out = crate::amm::calculate_out_given_in_with_fee(reserves.0, reserves.1, last_amount, fee).unwrap();
+
+    assert_eq!(worst, crate::amm::calculate_spot_price(last_amount, last_out, 1_000_000_000_000_000_000).unwrap());
+}
+
+#[test]
+fn impermanent_loss_should_work() {
+    let cases = vec![
+        (1_000_000_000_000_000_000, Ok(0), "Unchanged price"),
+        (4_000_000_000_000_000_000, Ok(200_000_000_000_000_000), "Price quadrupled"),
+        (250_000_000_000_000_000, Ok(200_000_000_000_000_000), "Price quartered"),
+        (2_000_000_000_000_000_000, Ok(57_190_958_417_936_635), "Price doubled"),
+        (0, Ok(1_000_000_000_000_000_000), "Price collapsed"),
+        (u128::MAX, Ok(999_999_999_891_579_783), "Price ratio at max"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::impermanent_loss(case.0), case.1, "{}", case.2);
+    }
+}
+
+#[test]
+fn implied_volatility_should_work() {
+    let year = 365 * 24 * 60 * 60;
+    let cases = vec![
+        (0, 41_685_152_500_090_131, year, Ok(800_000_000_000_000_008), "One year without fees"),
+        (10_000_000_000_000_000, 3_692_010_000_093_469, year / 4, Ok(500_000_000_000_000_024), "Quarter with fees"),
+        (0, 200_000_000_000_000_000, year, Ok(3_000_000_000_000_000_000), "Price quadrupled over a year"),
+        (0, 0, year, Ok(0), "No loss"),
+        (0, 1_000_000_000_000_000_000, year, Err(Overflow), "Total loss"),
+        (1_000_000_000_000_000_000, 500_000_000_000_000_000, year, Err(Overflow), "Fees and loss exceed the position"),
+        (0, 41_685_152_500_090_131, 0, Err(ZeroInReserve), "Zero period"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::implied_volatility(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn implied_volatility_should_round_trip_forward_loss() {
+    let fixed_one: u128 = 1_000_000_000_000_000_000;
+    let year: u128 = 365 * 24 * 60 * 60;
+    let cases = vec![
+        (800_000_000_000_000_000, year as u64, 0),
+        (500_000_000_000_000_000, (year / 4) as u64, 10_000_000_000_000_000),
+        (1_000_000_000_000_000_000, 30 * 24 * 60 * 60, 50_000_000_000_000_000),
+    ];
+
+    for (volatility, period_seconds, fee_apr) in cases {
+        // Forward: a one standard deviation move over the period, less the fees earned meanwhile
+        let period_years = period_seconds as u128 * fixed_one / year;
+        let sqrt_period = crate::math::integer_sqrt(U256::from(period_years) * U256::from(fixed_one)).low_u128();
+        let price_ratio = fixed_one + volatility * sqrt_period / fixed_one;
+        let observed_il = crate::amm::impermanent_loss(price_ratio).unwrap() - fee_apr * period_years / fixed_one;
+
+        let implied = crate::amm::implied_volatility(fee_apr, observed_il, period_seconds).unwrap();
+        let difference = implied.max(volatility) - implied.min(volatility);
+        assert!(difference <= volatility / 1_000_000_000_000, "{} vs {}", implied, volatility);
+    }
+}
+
+#[test]
+fn rebalance_threshold_should_work() {
+    let cases = vec![
+        (100_000_000_000_000_000, 1_000_000_000_000_000, 500_000_000_000_000_000, Ok(7_619_047_619_047_619), "Easy case"),
+        (100_000_000_000_000_000, 1_000_000_000_000_000, 0, Ok(10_000_000_000_000_000), "No volatility"),
+        (0, 1_000_000_000_000_000, 2_000_000_000_000_000_000, Ok(2_000_000_000_000_000), "No fees"),
+        (100_000_000_000_000_000, 0, 500_000_000_000_000_000, Ok(0), "Free rebalancing"),
+        (0, 1_000_000_000_000_000, 0, Err(ZeroInReserve), "Nothing to capture"),
+        (1, u128::MAX, 0, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::rebalance_threshold(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn rebalance_threshold_should_follow_gas_and_volatility() {
+    let (fee_apr, gas_cost, volatility) = (100_000_000_000_000_000, 1_000_000_000_000_000, 500_000_000_000_000_000);
+    let base = crate::amm::rebalance_threshold(fee_apr, gas_cost, volatility).unwrap();
+
+    assert!(crate::amm::rebalance_threshold(fee_apr, gas_cost * 2, volatility).unwrap() > base);
+    assert!(crate::amm::rebalance_threshold(fee_apr, gas_cost, volatility * 2).unwrap() < base);
+}
+
+#[test]
+fn validate_swap_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 600, 400_000_000_000_000_000, Ok(665), "Within both bounds"),
+        (1000, 2000, 500, 665, 333_333_333_333_333_333, Ok(665), "Exactly at both bounds"),
+        (1000, 2000, 500, 700, 400_000_000_000_000_000, Err(SlippageExceeded), "Output below minimum"),
+        (1000, 2000, 500, 600, 300_000_000_000_000_000, Err(ExcessivePriceImpact), "Impact above maximum"),
+        (1000, 2000, 500, 700, 300_000_000_000_000_000, Err(ExcessivePriceImpact), "Both bounds tripped"),
+        (0, 0, 0, 0, 0, Err(ZeroInReserve), "Empty pool"),
+        (u128::MAX, u128::MAX, u128::MAX, 0, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::validate_swap(case.0, case.1, case.2, case.3, case.4, (3, 1000)),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn round_trip_share_loss_should_work() {
+    let cases = vec![
+        (100, 200, 1000, 2000, 1000, Ok((0, 0)), "Exactly divisible deposit"),
+        (333, 666, 1000, 2000, 1000, Ok((0, 0)), "Divisible with one share per unit"),
+        (7, 14, 1000, 2000, 999, Ok((1, 2)), "Shares rounded down"),
+        (1, 1, 3, 7, 5, Ok((1, 1)), "Deposit below one share"),
+        (1_000_000, 2_000_000, 1_000_000_007, 2_000_000_013, 1_000_000_003, Ok((1, 2)), "Large uneven pool"),
+        (0, 0, 1000, 1000, 1000, Ok((0, 0)), "Zero deposit"),
+        (100, 100, 0, 1000, 1000, Err(ZeroInReserve), "Zero reserve"),
+        (1, 1, 1000, 1000, 0, Err(ZeroInReserve), "No shares outstanding"),
+        (u128::MAX, u128::MAX, u128::MAX, u128::MAX, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::round_trip_share_loss(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn round_trip_share_loss_should_stay_within_a_few_units() {
+    let (reserve_a, reserve_b, total_shares) = (1_000_003, 2_000_011, 999_983);
+
+    for amount_a in (1..10_000).step_by(97) {
+        let amount_b = crate::amm::calculate_liquidity_in(reserve_a, reserve_b, amount_a).unwrap() + 1;
+        let (lost_a, lost_b) =
+            crate::amm::round_trip_share_loss(amount_a, amount_b, reserve_a, reserve_b, total_shares).unwrap();
+
+        assert!(lost_a <= 2 && lost_b <= 4, "{}: lost ({}, {})", amount_a, lost_a, lost_b);
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn price_confidence_band_should_work() {
+    let one = 1_000_000_000_000_000_000;
+    let milli = 1_000_000_000_000_000;
+    let cases = vec![
+        (
+            vec![one, one + milli, one - milli, one, one + 2 * milli, one - 2 * milli],
+            Ok((998_709_005_551_264_195, 1_001_290_994_448_735_805)),
+            "Stable series",
+        ),
+        (vec![one, one, one, 5 * one, one, one], Ok((175_954_681_666_806_869, 3_157_378_651_666_526_463)), "Spiky series"),
+        (vec![one], Ok((one, one)), "Single sample"),
+        (vec![u128::MAX; 3], Ok((u128::MAX, u128::MAX)), "Constant series at max"),
+        (vec![], Err(ZeroInReserve), "No samples"),
+        (vec![0, u128::MAX, u128::MAX], Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::price_confidence_band(&case.0), case.1, "{}", case.2);
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn price_confidence_band_should_widen_with_spikes() {
+    let one = 1_000_000_000_000_000_000;
+    let (stable_lower, stable_upper) = crate::amm::price_confidence_band(&[one, one + 1_000, one - 1_000, one]).unwrap();
+    let (spiky_lower, spiky_upper) = crate::amm::price_confidence_band(&[one, 3 * one, one / 3, one]).unwrap();
+
+    assert!(stable_upper - stable_lower < 2_000);
+    assert!(spiky_upper - spiky_lower > one);
+    // A manipulated price falls outside of the stable band
+    assert!(one + one / 100 > stable_upper);
+}
+
+#[test]
+fn il_tolerance_band_should_work() {
+    let cases = vec![
+        (2_000_000_000_000_000_000, 200_000_000_000_000_000, Ok((500_000_000_000_000_000, 8_000_000_000_000_000_000)), "Easy case"),
+        (500_000_000_000_000_000, 57_190_958_417_936_635, Ok((250_000_000_000_000_000, 1_000_000_000_000_000_003)), "Cheap asset a"),
+        (3_000_000_000_000_000_000, 10_000_000_000_000_000, Ok((2_258_234_711_988_620_486, 3_985_413_895_296_350_433)), "Narrow band rounded inwards"),
+        (2_000_000_000_000_000_000, 0, Ok((2_000_000_000_000_000_000, 2_000_000_000_000_000_000)), "No tolerance"),
+        (0, 200_000_000_000_000_000, Err(ZeroInReserve), "Zero price"),
+        (2_000_000_000_000_000_000, 1_000_000_000_000_000_000, Err(Overflow), "Total loss tolerated"),
+        (u128::MAX, 200_000_000_000_000_000, Err(Overflow), "Band above range"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::il_tolerance_band(case.0, case.1), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+fn reserves_for_il_tolerance_should_work() {
+    let cases = vec![
+        (2_000_000_000_000_000_000, 200_000_000_000_000_000, 1_000_000, Ok((250_000, 500_000)), "Easy case"),
+        (500_000_000_000_000_000, 57_190_958_417_936_635, 1_000_000, Ok((1_000_000, 500_000)), "Cheap asset a"),
+        (3_000_000_000_000_000_000, 10_000_000_000_000_000, 1_000_000, Ok((166_666, 500_000)), "Truncated reserve a"),
+        (2_000_000_000_000_000_000, 0, 1_000_000, Ok((250_000, 500_000)), "No tolerance"),
+        (2_000_000_000_000_000_000, 200_000_000_000_000_000, 0, Ok((0, 0)), "No capital"),
+        (0, 200_000_000_000_000_000, 1_000_000, Err(ZeroInReserve), "Zero price"),
+        (2_000_000_000_000_000_000, 1_000_000_000_000_000_000, 1_000_000, Err(Overflow), "Total loss tolerated"),
+        (u128::MAX, 200_000_000_000_000_000, 1_000_000, Err(Overflow), "Band above range"),
+        (1, 200_000_000_000_000_000, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::reserves_for_il_tolerance(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn il_tolerance_band_should_match_max_il_at_its_edges() {
+    let fixed_one = U256::from(1_000_000_000_000_000_000u128);
+    let cases: Vec<(u128, u128)> = vec![
+        (3_000_000_000_000_000_000, 200_000_000_000_000_000),
+        (3_000_000_000_000_000_000, 10_000_000_000_000_000),
+        (500_000_000_000_000_000, 1_000_000_000_000_000),
+        (123_456_789_000_000_000_000, 500_000_000_000_000_000),
+    ];
+
+    for (price, max_il) in cases {
+        let (reserve_a, reserve_b) = crate::amm::reserves_for_il_tolerance(price, max_il, 1_000_000_000_000_000_000_000_000).unwrap();
+        let (lowest_price, highest_price) = crate::amm::il_tolerance_band(price, max_il).unwrap();
+        let (a_hp, b_hp) = (U256::from(reserve_a), U256::from(reserve_b));
+
+        for &edge_price in [lowest_price, highest_price].iter() {
+            // Reserves after arbitrage moved the pool to the edge price along the constant product curve
+            let (k_hp, edge_price_hp) = (a_hp * b_hp, U256::from(edge_price));
+            let edge_a_hp = crate::math::integer_sqrt(k_hp * fixed_one / edge_price_hp);
+            let edge_b_hp = crate::math::integer_sqrt(k_hp * edge_price_hp / fixed_one);
+
+            let pool_value_hp = edge_a_hp * edge_price_hp / fixed_one + edge_b_hp;
+            let hodl_value_hp = a_hp * edge_price_hp / fixed_one + b_hp;
+            let il = (fixed_one - pool_value_hp * fixed_one / hodl_value_hp).low_u128();
+
+            let difference = il.max(max_il) - il.min(max_il);
+            assert!(difference <= max_il / 1_000_000_000, "{} vs {}", il, max_il);
+        }
+
+        // The band is rounded inwards, so the loss at its edges stays under the tolerance,
+        // up to the unit `impermanent_loss` itself rounds the loss up by
+        let edge_ratio = (U256::from(highest_price) * fixed_one / U256::from(price)).low_u128();
+        assert!(crate::amm::impermanent_loss(edge_ratio).unwrap() <= max_il + 1, "{}", max_il);
+        let wider_ratio = edge_ratio + edge_ratio / 1_000_000_000;
+        assert!(crate::amm::impermanent_loss(wider_ratio).unwrap() > max_il, "{}", max_il);
+    }
+}
+
+#[test]
+fn calculate_out_given_in_fresh_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 3, 10, Ok(665), "Fresh reserves"),
+        (1000, 2000, 500, 10, 10, Ok(665), "Reserves at max age"),
+        (1000, 2000, 500, 11, 10, Err(StaleReserves), "Reserves one block too old"),
+        (1000, 2000, 500, 0, 0, Ok(665), "Same block only"),
+        (1000, 2000, 500, u64::MAX, 0, Err(StaleReserves), "Never updated"),
+        (0, 0, 0, 0, 10, Err(ZeroInReserve), "Empty pool"),
+        (u128::MAX, u128::MAX, u128::MAX, 0, 10, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_fresh(case.0, case.1, case.2, case.3, case.4, (3, 1000)),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn marginal_fee_yield_should_work() {
+    let cases = vec![
+        (5_000, 1_000, (3, 1000), Ok(15_000_000_000_000_000), "Easy case"),
+        (1_000, 3_000, (3, 1000), Ok(1_000_000_000_000_000), "Deep pool"),
+        (5_000, 1_000, (0, 1000), Ok(0), "No fee"),
+        (0, 1_000, (3, 1000), Ok(0), "No volume"),
+        (5_000, 0, (3, 1000), Err(ZeroInReserve), "Zero reserve"),
+        (5_000, 1_000, (3, 0), Err(InvalidFee), "Zero fee denominator"),
+        (5_000, 1_000, (1001, 1000), Err(InvalidFee), "Fee above whole amount"),
+        (u128::MAX, 1, (3, 1000), Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::marginal_fee_yield(case.0, case.1, case.2), case.3, "{}", case.4);
+    }
+}
+
+#[test]
+fn marginal_fee_yield_should_follow_volume_fee_and_depth() {
+    let (volume, reserve, fee) = (1_000_000, 10_000_000, (3, 1000));
+    let base = crate::amm::marginal_fee_yield(volume, reserve, fee).unwrap();
+
+    assert!(crate::amm::marginal_fee_yield(volume * 2, reserve, fee).unwrap() > base);
+    assert!(crate::amm::marginal_fee_yield(volume, reserve, (5, 1000)).unwrap() > base);
+    assert!(crate::amm::marginal_fee_yield(volume, reserve * 2, fee).unwrap() < base);
+}
+
+#[test]
+fn anchored_split_should_work() {
+    let anchor = 2_000_000_000_000_000_000;
+    let cases = vec![
+        ((1_000_000_000, 2_000_000_000), (1_000_000_000, 2_000_000_000), 100_000_000, anchor, Ok((50_000_000, 50_000_000)), "Identical pools"),
+        ((1_000_000_000, 2_000_000_000), (100_000_000_000, 200_000_000_000), 1_000_000_000, anchor, Ok((99_990, 999_900_010)), "Shallow and deep pool"),
+        ((1_000_000_000, 2_200_000_000), (1_000_000_000, 2_000_000_000), 100_000_000, anchor, Ok((75_658_181, 24_341_819)), "First pool above anchor"),
+        ((1000, 1800), (1000, 2200), 100, anchor, Ok((0, 100)), "First pool below anchor"),
+        ((1000, 2000), (100_000, 200_000), 1000, anchor, Ok((0, 1000)), "Small shallow pool"),
+        ((1000, 2000), (1000, 2000), 0, anchor, Ok((0, 0)), "Zero amount"),
+        ((0, 0), (1000, 2000), 1000, anchor, Err(ZeroInReserve), "Empty pool"),
+        ((1000, 2000), (1000, 2000), 1000, 0, Err(ZeroInReserve), "Zero anchor"),
+        ((u128::MAX, 1), (u128::MAX, 1), 1, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::anchored_split(case.0, case.1, case.2, case.3, (3, 1000)), case.4, "{}", case.5);
+    }
+
+    assert_eq!(crate::amm::anchored_split((1000, 2000), (1000, 2000), 1000, anchor, (1, 0)), Err(InvalidFee), "Invalid fee");
+
+    // Without a fee and with both pools at the anchor, each deviation is AMOUNT / IN_RESERVE,
+    // so the cost is minimized at AMOUNT_A = AMOUNT_IN * IN_A^2 / (IN_A^2 + IN_B^2), a fifth of the trade here
+    assert_eq!(
+        crate::amm::anchored_split((1_000_000_000, 2_000_000_000), (2_000_000_000, 4_000_000_000), 100_000_000, anchor, (0, 1000)),
+        Ok((20_000_000, 80_000_000)),
+        "Split by squared depth without fee"
+    );
+}
+
+#[test]
+fn anchored_split_should_match_brute_force_minimum() {
+    let fixed_one = U256::from(1_000_000_000_000_000_000u128);
+    // Squared deviation of SQRT(ANCHOR / PRICE) from one after selling `amount` on the fee curve, as anchored_split measures it
+    let deviation = |(in_reserve, out_reserve): (u128, u128), amount: u128, anchor: u128, (fee_numerator, fee_denominator): (u32, u32)| {
+        let (x, y, t, d, g) = (U256::from(in_reserve), U256::from(out_reserve), U256::from(amount), U256::from(fee_denominator), U256::from(fee_denominator - fee_numerator));
+        let in_growth = (x + t) * fixed_one / x;
+        let out_shrink = (d * x + g * t) * U256::from(anchor) / (d * y);
+        let relative = crate::math::integer_sqrt(in_growth * out_shrink);
+        let deviation = if relative > fixed_one { relative - fixed_one } else { fixed_one - relative };
+        deviation * deviation
+    };
+
+    let cases = vec![
+        ((1_000, 2_000), (5_000, 10_000), 3_000, 2_000_000_000_000_000_000u128, (3, 1000)),
+        ((1_000, 2_600), (4_000, 7_000), 2_000, 2_000_000_000_000_000_000, (3, 1000)),
+        ((1_000, 1_500), (2_000, 5_000), 1_500, 2_000_000_000_000_000_000, (0, 1000)),
+        ((300, 900), (700, 1_100), 5_000, 2_000_000_000_000_000_000, (1, 100)),
+        ((2_000, 1_000), (500, 1_000), 4_000, 500_000_000_000_000_000, (3, 1000)),
+        ((100, 10_000), (10_000, 100), 2_500, 1_000_000_000_000_000_000, (3, 1000)),
+    ];
+
+    for (pool_a, pool_b, amount_in, anchor, fee) in cases {
+        let cost = |to_a: u128| deviation(pool_a, to_a, anchor, fee) + deviation(pool_b, amount_in - to_a, anchor, fee);
+        let minimum = (0..=amount_in).map(cost).min().unwrap();
+
+        let (to_a, to_b) = crate::amm::anchored_split(pool_a, pool_b, amount_in, anchor, fee).unwrap();
+        assert_eq!(to_a + to_b, amount_in);
+        assert_eq!(cost(to_a), minimum, "{:?} {:?} {}", pool_a, pool_b, amount_in);
+    }
+}
+
+#[test]
+fn anchored_split_should_stay_closer_to_anchor_than_output_maximization() {
+    let (pool_a, pool_b, amount_in, anchor, fee) =
+        ((1_000_000_000, 2_000_000_000), (100_000_000_000, 200_000_000_000), 1_000_000_000, 2_000_000_000_000_000_000u128, (3, 1000));
+
+    let price_after = |(in_reserve, out_reserve): (u128, u128), amount: u128| {
+        let amount_out = crate::amm::calculate_out_given_in_with_fee(in_reserve, out_reserve, amount, fee).unwrap();
+        (U256::from(out_reserve - amount_out) * U256::from(1_000_000_000_000_000_000u128) / U256::from(in_reserve + amount)).low_u128()
+    };
+    let deviation = |price: u128| price.max(anchor) - price.min(anchor);
+    let squared_deviation = |to_a: u128| {
+        let deviation_a = U256::from(deviation(price_after(pool_a, to_a)));
+        let deviation_b = U256::from(deviation(price_after(pool_b, amount_in - to_a)));
+        deviation_a * deviation_a + deviation_b * deviation_b
+    };
+
+    let (anchored_to_a, _) = crate::amm::anchored_split(pool_a, pool_b, amount_in, anchor, fee).unwrap();
+    let (max_output_to_a, _, _) =
+        crate::amm::best_split_across_tiers((pool_a.0, pool_a.1, fee), (pool_b.0, pool_b.1, fee), amount_in).unwrap();
+
+    assert!(squared_deviation(anchored_to_a) < squared_deviation(max_output_to_a));
+    // The shallow pool is left much closer to the anchor
+    assert!(deviation(price_after(pool_a, anchored_to_a)) * 10 < deviation(price_after(pool_a, max_output_to_a)));
+}
+
+#[test]
+fn leveraged_liquidation_price_should_work() {
+    let cases = vec![
+        (1000, 2000, 2000, 100, 100, Ok((500_000_000_000_000_000, u128::MAX)), "2x leveraged position"),
+        (1000, 2000, 1000, 100, 100, Ok((125_000_000_000_000_000, u128::MAX)), "1.33x leveraged position"),
+        (1000, 2000, 1000, 50, 100, Ok((500_000_000_000_000_000, u128::MAX)), "Half of the pool"),
+        (1000, 2000, 0, 100, 100, Ok((0, u128::MAX)), "No debt"),
+        (1000, 2000, 1000, 0, 100, Err(ZeroInReserve), "No shares"),
+        (1000, 2000, 1000, 100, 0, Err(ZeroInReserve), "No shares outstanding"),
+        (1, 1, u128::MAX, 1, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::leveraged_liquidation_price(case.0, case.1, case.2, case.3, case.4),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn leveraged_liquidation_price_should_tighten_with_leverage() {
+    let (reserve_a, reserve_b, shares, total_shares) = (1_000_000_000, 2_000_000_000, 1_000, 1_000);
+    let fixed_one = 1_000_000_000_000_000_000;
+    // The position is worth 4_000_000_000 in asset b, so borrowing half of it is 2x leverage
+    let (unleveraged, unleveraged_upper) = crate::amm::leveraged_liquidation_price(reserve_a, reserve_b, 0, shares, total_shares).unwrap();
+    let (leveraged, leveraged_upper) = crate::amm::leveraged_liquidation_price(reserve_a, reserve_b, 2_000_000_000, shares, total_shares).unwrap();
+    let (more_leveraged, more_leveraged_upper) =
+        crate::amm::leveraged_liquidation_price(reserve_a, reserve_b, 3_000_000_000, shares, total_shares).unwrap();
+
+    assert!(unleveraged < leveraged && leveraged < more_leveraged);
+    // A debt in asset b never liquidates on the upside
+    assert!(unleveraged_upper == u128::MAX && leveraged_upper == u128::MAX && more_leveraged_upper == u128::MAX);
+
+    for &(borrowed_b, liquidation_price) in [(2_000_000_000u128, leveraged), (3_000_000_000, more_leveraged)].iter() {
+        let position_value_at = |price: u128| {
+            let (position_a, position_b) =
+                crate::amm::position_value_after_price_move(reserve_a, reserve_b, shares, total_shares, price / 2).unwrap();
+            position_a * price / fixed_one + position_b
+        };
+
+        // At the liquidation price the position is worth its debt, below it less and above it more
+        let value = position_value_at(liquidation_price);
+        let difference = value.max(borrowed_b) - value.min(borrowed_b);
+        assert!(difference <= 2, "{}", borrowed_b);
+        assert!(position_value_at(liquidation_price * 99 / 100) < borrowed_b, "{}", borrowed_b);
+        assert!(position_value_at(liquidation_price * 10) > borrowed_b, "{}", borrowed_b);
+    }
+}
+
+#[test]
+fn calculate_fees_accrued_should_work() {
+    let cases = vec![
+        (1000, U256::from(1_000_000), 1100, 1100, Ok(15), "K grew"),
+        (1_000_000_000_000_000_000, U256::from(10).pow(U256::from(36)), 2_000_000_000_000_000_000, 2_000_000_000_000_000_000, Ok(90_909_090_909_090_909), "K doubled its root"),
+        (1000, U256::from(1_000_000), 1000, 1000, Ok(0), "K unchanged"),
+        (1000, U256::from(1_000_000), 900, 900, Ok(0), "K shrank"),
+        (1000, U256::zero(), 2000, 2000, Ok(0), "Protocol fee off"),
+        (1000, U256::from(1_000_000), 0, 0, Ok(0), "Empty pool"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::calculate_fees_accrued(case.0, case.1, case.2, case.3), case.4, "{}", case.5);
+    }
+}
+
+#[test]
+fn apply_protocol_fee_mint_should_work() {
+    let cases = vec![
+        (1000, U256::from(1_000_000), 1100, 1100, Ok(1015), "K grew"),
+        (1000, U256::from(1_000_000), 1000, 1000, Ok(1000), "K unchanged"),
+        (1000, U256::from(1_000_000), 900, 900, Ok(1000), "K shrank"),
+        (1000, U256::zero(), 2000, 2000, Ok(1000), "Protocol fee off"),
+        (u128::MAX, U256::one(), u128::MAX, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::apply_protocol_fee_mint(case.0, case.1, case.2, case.3), case.4, "{}", case.5);
+    }
+}
+
+#[test]
+fn calculate_out_given_in_with_bounds_should_work() {
+    // Bounds on the price of asset a in asset b of a pool holding 1000 a and 2000 b
+    let (floor, ceiling) = (1_000_000_000_000_000_000, 4_000_000_000_000_000_000);
+    let (flipped_floor, flipped_ceiling) = (250_000_000_000_000_000, 1_000_000_000_000_000_000);
+    let cases = vec![
+        (1000, 2000, 100, floor, ceiling, Ok(181), "Sell within bounds"),
+        (1000, 2000, 414, floor, ceiling, Ok(584), "Sell just above floor"),
+        (1000, 2000, 500, floor, ceiling, Err(PriceBoundBreached), "Big sell trips floor"),
+        (2000, 1000, 100, flipped_floor, flipped_ceiling, Ok(47), "Buy within bounds"),
+        (2000, 1000, 2000, flipped_floor, flipped_ceiling, Err(PriceBoundBreached), "Big buy trips ceiling"),
+        (1000, 2000, 100, floor, 1_500_000_000_000_000_000, Err(PriceBoundBreached), "Pool above ceiling"),
+        (0, 0, 0, floor, ceiling, Err(ZeroInReserve), "Empty pool"),
+        (u128::MAX, u128::MAX, u128::MAX, floor, ceiling, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_with_bounds(case.0, case.1, case.2, case.3, case.4, (3, 1000)),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+fn recoverable_value_should_work() {
+    let cases = vec![
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, 1, 1, Ok(2_000_000_000_000), "On peg"),
+        (1_000_000_000_000, 1_000_000_000_000, 900_000_000_000_000_000, 1, 1, Ok(1_897_366_596_100), "Partial depeg"),
+        (1_000_000_000_000, 1_000_000_000_000, 500_000_000_000_000_000, 1, 1, Ok(1_414_213_562_372), "Half depeg"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000, 1, 1, Ok(2_000_000_000), "Full depeg"),
+        (1_000_000_000_000, 1_000_000_000_000, 0, 1, 1, Ok(0), "Worthless asset b"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, 1, 4, Ok(500_000_000_000), "Quarter of the pool"),
+        (1_000_000_000_000, 1_000_000_000_000, 1_000_000_000_000_000_000, 1, 0, Err(ZeroInReserve), "No shares outstanding"),
+        (u128::MAX, u128::MAX, u128::MAX, 1, 1, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::recoverable_value(case.0, case.1, case.2, case.3, case.4), case.5, "{}", case.6);
+    }
+}
+
+#[test]
+fn recoverable_value_should_drop_with_the_peg() {
+    let (reserve_a, reserve_b) = (1_000_000_000_000, 1_000_000_000_000);
+    let mut previous = u128::MAX;
+
+    for &price in [1_000_000_000_000_000_000u128, 990_000_000_000_000_000, 900_000_000_000_000_000, 500_000_000_000_000_000, 0].iter() {
+        let value = crate::amm::recoverable_value(reserve_a, reserve_b, price, 1, 1).unwrap();
+        assert!(value < previous);
+        // Arbitrage leaves less than the reserves are worth at the true price
+        assert!(value <= reserve_a + reserve_b * price / 1_000_000_000_000_000_000);
+        previous = value;
+    }
+}
+
+#[test]
+fn two_sided_trade_should_work() {
+    let cases = vec![
+        (1000, 2000, 100, 150, Ok((74, 197)), "Asset a side larger"),
+        (1000, 2000, 50, 300, Ok((139, 99)), "Asset b side larger"),
+        (1000, 2000, 100, 200, Ok((99, 199)), "Fully matched"),
+        (1000, 2000, 100, 0, Ok((0, 181)), "One-sided trade"),
+        (1000, 2000, 0, 0, Ok((0, 0)), "Zero amounts"),
+        (0, 2000, 100, 150, Err(ZeroInReserve), "Zero reserve"),
+        (u128::MAX, u128::MAX, u128::MAX, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::two_sided_trade(case.0, case.1, case.2, case.3, (3, 1000)), case.4, "{}", case.5);
+    }
+}
+
+#[test]
+fn two_sided_trade_should_beat_separate_swaps() {
+    let (reserve_a, reserve_b, fee) = (1_000_000_000_000, 2_000_000_000_000, (3, 1000));
+    let (amount_a_in, amount_b_in) = (100_000_000_000, 100_000_000_000);
+
+    let (amount_a_out, amount_b_out) = crate::amm::two_sided_trade(reserve_a, reserve_b, amount_a_in, amount_b_in, fee).unwrap();
+
+    // Each side swapped on its own against the untouched reserves
+    let separate_b_out = crate::amm::calculate_out_given_in_with_fee(reserve_a, reserve_b, amount_a_in, fee).unwrap();
+    let separate_a_out = crate::amm::calculate_out_given_in_with_fee(reserve_b, reserve_a, amount_b_in, fee).unwrap();
+
+    assert!(amount_a_out > separate_a_out);
+    assert!(amount_b_out > separate_b_out);
+}
+
+#[test]
+fn min_backrun_size_should_work() {
+    let reserve = 1_000_000_000_000;
+    let cases = vec![
+        (reserve, reserve, 100_000_000_000, 1_000_000, Ok(4_853_560), "Large prior swap"),
+        (reserve, reserve, 100_000_000_000, 1_000_000_000, Ok(5_013_853_389), "Large prior swap with costly gas"),
+        (reserve, reserve, 100_000_000_000, 10_000_000_000, Ok(0), "Gas above the arbitrage profit"),
+        (reserve, reserve, 1_000_000_000, 1_000_000, Ok(0), "Prior swap within the fees"),
+        (reserve, reserve, 1_000_000, 1_000_000, Ok(0), "Tiny prior swap"),
+        (0, reserve, 1_000_000, 1_000_000, Err(ZeroInReserve), "Zero reserve"),
+        (u128::MAX, u128::MAX, u128::MAX, 0, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::min_backrun_size(case.0, case.1, case.2, (3, 1000), case.3),
+            case.4,
+            "{}",
+            case.5
+        );
+    }
+
+    assert_eq!(crate::amm::min_backrun_size(1000, 2000, 100, (3, 0), 0), Err(InvalidFee), "Zero fee denominator");
+}
+
+#[test]
+fn min_backrun_size_should_be_the_smallest_profitable_amount() {
+    let (reserve_in, reserve_out, prior_swap_amount_in, fee, gas) =
+        (1_000_000_000_000, 2_000_000_000_000, 50_000_000_000, (3, 1000), 5_000_000);
+
+    let backrun = crate::amm::min_backrun_size(reserve_in, reserve_out, prior_swap_amount_in, fee, gas).unwrap();
+
+    let prior_out = crate::amm::calculate_out_given_in_with_fee(reserve_in, reserve_out, prior_swap_amount_in, fee).unwrap();
+    let profit = |amount| {
+        let received = crate::amm::calculate_out_given_in_with_fee(reserve_out - prior_out, reserve_in + prior_swap_amount_in, amount, fee).unwrap();
+        crate::amm::calculate_spot_price(reserve_in, reserve_out, received).unwrap() as i128 - amount as i128 - gas as i128
+    };
+
+    assert!(backrun > 0);
+    assert!(profit(backrun) > 0);
+    assert!(profit(backrun - 1) <= 0);
+}
+
+#[test]
+fn lvr_estimate_should_work() {
+    let reserve = 1_000_000_000_000;
+    let cases = vec![
+        (reserve, reserve, 800_000_000_000_000_000, (0, 1000), Ok(160_000_000_000), "No fee"),
+        (reserve, reserve, 800_000_000_000_000_000, (1, 10000), Ok(124_361_230_430), "Low fee"),
+        (reserve, reserve, 800_000_000_000_000_000, (3, 1000), Ok(16_671_463_309), "Easy case"),
+        (reserve, reserve, 800_000_000_000_000_000, (30, 10000), Ok(16_671_463_309), "Same fee, other denominator"),
+        (reserve, 2 * reserve, 1_000_000_000_000_000_000, (3, 1000), Ok(63_469_574_365), "Pool valued in asset b"),
+        (reserve, reserve, 0, (3, 1000), Ok(0), "No volatility"),
+        (0, reserve, 800_000_000_000_000_000, (3, 1000), Err(ZeroInReserve), "Zero reserve"),
+        (reserve, reserve, 800_000_000_000_000_000, (3, 0), Err(InvalidFee), "Zero fee denominator"),
+        (reserve, reserve, 800_000_000_000_000_000, (1001, 1000), Err(InvalidFee), "Fee above whole amount"),
+        (u128::MAX, u128::MAX, u128::MAX, (0, 1000), Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::lvr_estimate(case.0, case.1, case.2, case.3), case.4, "{}", case.5);
+    }
+}
+
+#[test]
+fn lvr_estimate_should_follow_volatility_and_fee() {
+    let (reserve_a, reserve_b, volatility) = (1_000_000_000_000, 1_000_000_000_000, 500_000_000_000_000_000);
+
+    // Without fees LVR is quadratic in volatility
+    let base = crate::amm::lvr_estimate(reserve_a, reserve_b, volatility, (0, 1000)).unwrap();
+    let doubled = crate::amm::lvr_estimate(reserve_a, reserve_b, volatility * 2, (0, 1000)).unwrap();
+    assert_eq!(doubled, base * 4);
+
+    let with_fee = crate::amm::lvr_estimate(reserve_a, reserve_b, volatility, (3, 1000)).unwrap();
+    let with_higher_fee = crate::amm::lvr_estimate(reserve_a, reserve_b, volatility, (10, 1000)).unwrap();
+    assert!(with_fee < base);
+    assert!(with_higher_fee < with_fee);
+}
+
+#[test]
+fn calculate_out_given_in_rate_limited_should_work() {
+    let cases = vec![
+        (1000, 2000, 500, 0, 1000, Ok(665), "Within cap"),
+        (1000, 2000, 500, 500, 1000, Ok(665), "Exactly at cap"),
+        (1000, 2000, 500, 501, 1000, Err(VolumeCapExceeded), "One unit over cap"),
+        (1000, 2000, 500, 0, 499, Err(VolumeCapExceeded), "Single swap over cap"),
+        (1000, 2000, 500, u128::MAX, u128::MAX, Err(VolumeCapExceeded), "Used volume at max"),
+        (0, 0, 0, 0, 1000, Err(ZeroInReserve), "Empty pool"),
+        (1, u128::MAX, u128::MAX, 0, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(
+            crate::amm::calculate_out_given_in_rate_limited(case.0, case.1, case.2, case.3, case.4, (3, 1000)),
+            case.5,
+            "{}",
+            case.6
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn simulate_sequence_should_work() {
+    use crate::amm::{Op, PoolState};
+
+    let pool = |reserve_a, reserve_b, total_shares| PoolState { reserve_a, reserve_b, total_shares };
+    let initial = pool(1_000_000, 2_000_000, 1_000_000);
+    let cases = vec![
+        (
+            initial,
+            vec![Op::SwapAForB(100_000), Op::AddLiquidity(50_000, 90_000), Op::SwapBForA(200_000), Op::RemoveLiquidity(300_000)],
+            Ok(pool(742_438, 1_503_579, 745_454)),
+            "Known sequence",
+        ),
+        (initial, vec![Op::SwapAForB(100_000)], Ok(pool(1_100_000, 1_818_678, 1_000_000)), "Single swap"),
+        (initial, vec![Op::AddLiquidity(100_000, 200_000)], Ok(pool(1_100_000, 2_200_000, 1_100_000)), "Balanced deposit"),
+        (initial, vec![Op::RemoveLiquidity(1_000_000)], Ok(pool(0, 0, 0)), "Withdraw everything"),
+        (initial, vec![], Ok(initial), "No operations"),
+        (initial, vec![Op::RemoveLiquidity(1_000_001)], Err(InsufficientLiquidity), "Burning more than total shares"),
+        (pool(0, 0, 0), vec![Op::AddLiquidity(100, 100)], Err(ZeroInReserve), "Deposit to empty pool"),
+        (initial, vec![Op::SwapAForB(u128::MAX)], Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::simulate_sequence(case.0, &case.1, (3, 1000)), case.2, "{}", case.3);
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn simulate_sequence_should_match_step_by_step_calculations() {
+    use crate::amm::{Op, PoolState};
+
+    let initial = PoolState { reserve_a: 1_000_000_000, reserve_b: 3_000_000_000, total_shares: 500_000_000 };
+    let fee = (3, 1000);
+
+    let state = crate::amm::simulate_sequence(initial, &[Op::SwapBForA(250_000_000), Op::SwapAForB(40_000_000)], fee).unwrap();
+
+    let a_out = crate::amm::calculate_out_given_in_with_fee(3_000_000_000, 1_000_000_000, 250_000_000, fee).unwrap();
+    let (reserve_a, reserve_b) = (1_000_000_000 - a_out, 3_250_000_000);
+    let b_out = crate::amm::calculate_out_given_in_with_fee(reserve_a, reserve_b, 40_000_000, fee).unwrap();
+
+    assert_eq!(state, PoolState { reserve_a: reserve_a + 40_000_000, reserve_b: reserve_b - b_out, total_shares: 500_000_000 });
+}
+
+#[test]
+fn calculate_spot_price_weighted_should_work() {
+    let cases = vec![
+        (1_000_000, 80, 4_000_000, 20, 1_000, Ok(16_000), "80/20 pool"),
+        (4_000_000, 20, 1_000_000, 80, 1_000, Ok(62), "20/80 pool"),
+        (1_000_000, 50, 4_000_000, 50, 1_000, Ok(4_000), "Equal weights"),
+        (1_000_000, 80, 4_000_000, 20, 0, Ok(0), "Zero amount"),
+        (1_000_000, 80, 0, 20, 1_000, Ok(0), "Zero out reserve"),
+        (0, 80, 4_000_000, 20, 1_000, Err(ZeroInReserve), "Zero in reserve"),
+        (1_000_000, 0, 4_000_000, 20, 1_000, Err(ZeroInReserve), "Zero weight"),
+        (1, 80, u128::MAX, 20, u128::MAX, Err(Overflow), "Overflow"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::calculate_spot_price_weighted(case.0, case.1, case.2, case.3, case.4), case.5, "{}", case.6);
+    }
+}
+
+#[test]
+fn calculate_out_given_in_weighted_should_work() {
+    // Reference values are OUT_RESERVE * (1 - (IN_RESERVE / (IN_RESERVE + AMOUNT_IN)) ^ (IN_WEIGHT / OUT_WEIGHT)) rounded down
+    let cases = vec![
+        (1_000_000_000_000_000_000_000, 80, 4_000_000_000_000_000_000_000, 20, 100_000_000_000_000_000_000, Ok(1_267_946_178_539_717_228_000), 1_267_946_178_539_717_232_429, "80/20 pool"),
+        (4_000_000_000_000_000_000_000, 20, 1_000_000_000_000_000_000_000, 80, 100_000_000_000_000_000_000, Ok(6_154_138_384_835_046_000), 6_154_138_384_835_046_809, "20/80 pool"),
+        (1_000_000_000_000_000_000_000, 80, 4_000_000_000_000_000_000_000, 20, 1_000_000_000_000_000_000_000, Ok(3_749_999_999_999_999_996_000), 3_750_000_000_000_000_000_000, "Doubling the in reserve"),
+        (1_000_000, 80, 4_000_000, 20, 1_000, Ok(15_960), 15_960, "Small 80/20 pool"),
+        (4_000_000, 20, 1_000_000, 80, 1_000, Ok(62), 62, "Small 20/80 pool"),
+        (1_000_000_000_000_000_000_000, 80, 4_000_000_000_000_000_000_000, 20, 0, Ok(0), 0, "Zero amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::calculate_out_given_in_weighted(case.0, case.1, case.2, case.3, case.4), case.5, "{}", case.7);
+        assert!(case.5.unwrap() <= case.6, "{}", case.7);
+    }
+
+    let errors = vec![
+        (0, 80, 4_000_000, 20, 0, ZeroInReserve, "Zero in reserve and amount"),
+        (1_000_000, 80, 4_000_000, 0, 1_000, ZeroInReserve, "Zero weight"),
+    ];
+
+    for case in errors {
+        assert_eq!(crate::amm::calculate_out_given_in_weighted(case.0, case.1, case.2, case.3, case.4), Err(case.5), "{}", case.6);
+    }
+}
+
+#[test]
+fn calculate_in_given_out_weighted_should_work() {
+    // Reference values are IN_RESERVE * ((OUT_RESERVE / (OUT_RESERVE - AMOUNT_OUT)) ^ (OUT_WEIGHT / IN_WEIGHT) - 1) rounded down
+    let cases = vec![
+        (4_000_000_000_000_000_000_000, 20, 1_000_000_000_000_000_000_000, 80, 100_000_000_000_000_000_000, Ok(6_349_525_306_027_369_000), 6_349_525_306_027_367_174, "80/20 pool"),
+        (1_000_000_000_000_000_000_000, 80, 4_000_000_000_000_000_000_000, 20, 100_000_000_000_000_000_000, Ok(2_096_631_611_034_903_240_000), 2_096_631_611_034_903_215_973, "20/80 pool"),
+        (4_000_000, 20, 1_000_000, 80, 1_000, Ok(63), 62, "Small 80/20 pool"),
+        (1_000_000, 80, 4_000_000, 20, 1_000, Ok(16_041), 16_040, "Small 20/80 pool"),
+        (4_000_000, 20, 1_000_000, 80, 0, Ok(0), 0, "Zero amount"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::calculate_in_given_out_weighted(case.0, case.1, case.2, case.3, case.4), case.5, "{}", case.7);
+        assert!(case.5.unwrap() >= case.6, "{}", case.7);
+    }
+
+    let errors = vec![
+        (4_000_000, 20, 1_000_000, 80, 4_000_001, InsufficientOutReserve, "Amount above out reserve"),
+        (4_000_000, 20, 1_000_000, 80, 4_000_000, ZeroInReserve, "Draining the out reserve"),
+        (4_000_000, 0, 1_000_000, 80, 1_000, ZeroInReserve, "Zero weight"),
+        (u128::MAX, 80, u128::MAX, 1, u128::MAX - 1, Overflow, "Overflow"),
+    ];
+
+    for case in errors {
+        assert_eq!(crate::amm::calculate_in_given_out_weighted(case.0, case.1, case.2, case.3, case.4), Err(case.5), "{}", case.6);
+    }
+}
+
+#[test]
+fn weighted_swaps_with_equal_weights_should_match_unweighted() {
+    let fixed_one = 1_000_000_000_000_000_000u128;
+    let cases = vec![
+        (1_000_000, 3_000_000, 10_000),
+        (1_000_000_000_000_000_000_000, 4_000_000_000_000_000_000_000, 100_000_000_000_000_000_000),
+        (123_456_789_012_345_678_901, 987_654_321_098_765_432_109, 55_555_555_555_555_555_555),
+        (u128::MAX / 4, u128::MAX / 2, u128::MAX / 8),
+        (1_000, 1_000, 1),
+    ];
+
+    for (in_reserve, out_reserve, amount) in cases {
+        let weighted = crate::amm::calculate_out_given_in_weighted(in_reserve, 50, out_reserve, 50, amount).unwrap();
+        let unweighted = crate::amm::calculate_out_given_in(in_reserve, out_reserve, amount).unwrap();
+        assert!(weighted <= unweighted);
+        assert!(unweighted - weighted <= 3 * (out_reserve / fixed_one) + 1);
+
+        let weighted = crate::amm::calculate_in_given_out_weighted(out_reserve, 50, in_reserve, 50, amount).unwrap();
+        let unweighted = crate::amm::calculate_in_given_out(out_reserve, in_reserve, amount).unwrap();
+        assert!(weighted >= unweighted);
+    }
+}
+
+#[test]
+fn calculate_initial_shares_should_work() {
+    let cases = vec![
+        (100, 100, Ok(100), "Equal amounts"),
+        (1_000, 4_000, Ok(2_000), "Perfect square product"),
+        (1_000, 4_001, Ok(2_000), "One above a perfect square product"),
+        (3, 3, Ok(3), "Small perfect square"),
+        (2, 4, Ok(2), "One below a perfect square product"),
+        (1_000_000_000_000_000_000, 2_000_000_000_000_000_000, Ok(1_414_213_562_373_095_048), "Irrational root"),
+        (0, 1_000, Ok(0), "Zero amount"),
+        (u128::MAX, 1, Ok(u64::MAX as u128), "Max amount a"),
+        (u128::MAX, u128::MAX - 1, Ok(u128::MAX - 1), "One below the max product"),
+        (u128::MAX, u128::MAX, Ok(u128::MAX), "Max amounts"),
+    ];
+
+    for case in cases {
+        assert_eq!(crate::amm::calculate_initial_shares(case.0, case.1), case.2, "{}", case.3);
+    }
+}